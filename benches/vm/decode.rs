@@ -1,7 +1,7 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use chip_8_rs::vm::VM;
 
-const codes: [u16; 34] = [
+const CODES: [u16; 34] = [
     0x8120,
     0xF133,
     0x8121,
@@ -39,7 +39,7 @@ const codes: [u16; 34] = [
 ];
 
 fn criterion_benchmark(c: &mut Criterion) {
-    for code in codes {
+    for code in CODES {
         c.bench_function(&format!("decode {}", code), |b| b.iter(|| VM::decode(black_box(code))));
     }
 }
@@ -0,0 +1,52 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use chip_8_rs::system::Interfaces;
+use chip_8_rs::threaded::ThreadedBackend;
+use chip_8_rs::vm::VM;
+
+const TICKS: u64 = 10_000;
+
+// counts V0 up forever, converting and drawing it as it goes
+const ROM: [u8; 20] = [
+    0x60, 0x00, // 0x200: V0 = 0
+    0x61, 0x01, // 0x202: V1 = 1
+    0x80, 0x14, // 0x204: V0 += V1
+    0x82, 0x06, // 0x206: V2 = V0 >> 1
+    0xA2, 0x50, // 0x208: I = 0x250
+    0xF2, 0x33, // 0x20A: BCD of V2 at I
+    0xD1, 0x25, // 0x20C: draw 5 rows at (V1, V2)
+    0x30, 0x00, // 0x20E: skip if V0 == 0
+    0x12, 0x04, // 0x210: jump 0x204
+    0x12, 0x00, // 0x212: jump 0x200
+];
+
+fn run(decode_cache: bool) {
+    let mut vm = VM::new();
+    vm.set_decode_cache(decode_cache);
+    vm.load_rom(ROM.to_vec());
+    let mut interfaces = Interfaces::new();
+    for _ in 0..TICKS {
+        vm.tick(&mut interfaces).unwrap();
+    }
+    black_box(&interfaces.screen);
+}
+
+fn run_threaded() {
+    let mut vm = VM::new();
+    vm.load_rom(ROM.to_vec());
+    let mut interfaces = Interfaces::new();
+    let mut backend = ThreadedBackend::new();
+    backend.run(&mut vm, &mut interfaces, TICKS as usize).unwrap();
+    black_box(&interfaces.screen);
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("execute rom");
+    group.throughput(Throughput::Elements(TICKS));
+    group.bench_function("decode every tick", |b| b.iter(|| run(false)));
+    group.bench_function("decode cache", |b| b.iter(|| run(true)));
+    group.bench_function("threaded", |b| b.iter(run_threaded));
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,459 @@
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+use std::fs::File;
+use std::io::{ BufWriter, Seek, SeekFrom, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
+
+use rodio::{ OutputStream, Sink, Source };
+
+use crate::frontend::{ AudioBackend, NullAudio };
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+// how far ahead of the speakers live samples are queued: two frames, enough to ride out a late
+// frame without the beeps lagging behind the picture
+const TARGET_LATENCY: usize = 2 * SAMPLE_RATE as usize / 60;
+// the most the queue can build up, when the vm runs ahead of real time, before it's cut back
+const MAX_LATENCY: usize = 3 * TARGET_LATENCY;
+
+/// The shape of the beep.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Waveform {
+    Square,
+    #[default]
+    Sine,
+    Triangle,
+}
+
+impl Waveform {
+    pub fn parse(waveform: &str) -> Result<Self, String> {
+        match waveform {
+            "square" => Ok(Self::Square),
+            "sine" => Ok(Self::Sine),
+            "triangle" => Ok(Self::Triangle),
+            _ => Err(format!("unknown waveform {}, expected square, sine or triangle", waveform)),
+        }
+    }
+
+    // one cycle, with the phase from 0 to 1
+    fn sample(&self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+        }
+    }
+}
+
+/// What the beeper sounds like. The attack and release are how long the beep takes to fade in
+/// and out, which stops it clicking.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone {
+    pub waveform: Waveform,
+    pub frequency: f32, // hz
+    pub volume: f32, // 0 to 1
+    pub attack: Duration,
+    pub release: Duration,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Sine,
+            frequency: 500.0,
+            volume: 0.2,
+            attack: Duration::from_millis(5),
+            release: Duration::from_millis(5),
+        }
+    }
+}
+
+impl Tone {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(20.0..=20000.0).contains(&self.frequency) {
+            return Err(format!("invalid frequency {}, expected 20 to 20000 hz", self.frequency));
+        }
+        if !(0.0..=1.0).contains(&self.volume) {
+            return Err(format!("invalid volume {}, expected a number from 0 to 1", self.volume));
+        }
+        Ok(())
+    }
+}
+
+/// An endless stream of samples that beeps while its gate is open. The gate and mute are shared
+/// so they can be flipped from outside, e.g. while rodio plays the generator on its own thread.
+pub struct Generator {
+    tone: Tone,
+    gate: Arc<AtomicBool>,
+    muted: Arc<AtomicBool>,
+    phase: f32,
+    envelope: f32,
+}
+
+impl Generator {
+    pub fn new(tone: Tone) -> Self {
+        Self {
+            tone,
+            gate: Arc::new(AtomicBool::new(false)),
+            muted: Arc::new(AtomicBool::new(false)),
+            phase: 0.0,
+            envelope: 0.0,
+        }
+    }
+
+    pub fn gate(&self) -> Arc<AtomicBool> {
+        self.gate.clone()
+    }
+
+    pub fn muted(&self) -> Arc<AtomicBool> {
+        self.muted.clone()
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        // the envelope ramps towards full while the gate's open and to silence when it's shut
+        let open = self.gate.load(Ordering::Relaxed) && !self.muted.load(Ordering::Relaxed);
+        let (target, ramp) = if open { (1.0, self.tone.attack) } else { (0.0, self.tone.release) };
+        let step = 1.0 / (ramp.as_secs_f32() * SAMPLE_RATE as f32).max(1.0);
+        self.envelope = if self.envelope < target {
+            (self.envelope + step).min(target)
+        } else {
+            (self.envelope - step).max(target)
+        };
+
+        let sample = self.tone.waveform.sample(self.phase) * self.envelope * self.tone.volume;
+        self.phase = (self.phase + self.tone.frequency / SAMPLE_RATE as f32).fract();
+        sample
+    }
+}
+
+impl Iterator for Generator {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        Some(self.next_sample())
+    }
+}
+
+impl Source for Generator {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The beeper switching on or off, at a point in emulated time: how many instructions the vm
+/// had run, over how many it runs a second. There's no XO-CHIP audio in the vm, so no pattern
+/// buffer or pitch changes to carry yet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SoundEvent {
+    pub time: Duration,
+    pub on: bool,
+}
+
+/// Turns sound events into samples, switching the beep on and off at the exact sample the
+/// event happened at rather than at the next frame.
+pub struct AudioRenderer {
+    generator: Generator,
+    gate: Arc<AtomicBool>,
+    events: VecDeque<SoundEvent>,
+    rendered: u64, // samples
+}
+
+impl AudioRenderer {
+    pub fn new(tone: Tone) -> Self {
+        let generator = Generator::new(tone);
+        Self {
+            gate: generator.gate(),
+            generator,
+            events: VecDeque::new(),
+            rendered: 0,
+        }
+    }
+
+    /// Queues an event. Events have to come in the order they happened.
+    pub fn push(&mut self, event: SoundEvent) {
+        self.events.push_back(event);
+    }
+
+    /// The samples from where the last call left off up to `until`. Events after that are kept
+    /// for the next call.
+    pub fn render(&mut self, until: Duration) -> Vec<f32> {
+        let end = Self::sample_at(until);
+        let mut samples = Vec::with_capacity(end.saturating_sub(self.rendered) as usize);
+        while self.rendered < end {
+            while let Some(event) = self.events.front().filter(|event| Self::sample_at(event.time) <= self.rendered) {
+                self.gate.store(event.on, Ordering::Relaxed);
+                self.events.pop_front();
+            }
+            samples.push(self.generator.next_sample());
+            self.rendered += 1;
+        }
+        samples
+    }
+
+    fn sample_at(time: Duration) -> u64 {
+        (time.as_secs_f64() * SAMPLE_RATE as f64) as u64
+    }
+}
+
+/// Writes 16 bit mono samples to a wav file. The sizes in the header are filled in by `finish`.
+pub struct WavWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path) -> Result<Self, String> {
+        let file = File::create(path).map_err(|error| format!("unable to create {}: {}", path.display(), error))?;
+        let mut wav = Self { path: path.to_path_buf(), file: BufWriter::new(file), samples: 0 };
+        wav.write_header().map_err(|error| format!("unable to write {}: {}", path.display(), error))?;
+        Ok(wav)
+    }
+
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let data_size = self.samples * 2;
+        self.file.write_all(b"RIFF")?;
+        self.file.write_all(&(36 + data_size).to_le_bytes())?;
+        self.file.write_all(b"WAVEfmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?;
+        self.file.write_all(&1u16.to_le_bytes())?; // pcm
+        self.file.write_all(&1u16.to_le_bytes())?; // mono
+        self.file.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        self.file.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?;
+        self.file.write_all(&2u16.to_le_bytes())?;
+        self.file.write_all(&16u16.to_le_bytes())?;
+        self.file.write_all(b"data")?;
+        self.file.write_all(&data_size.to_le_bytes())
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        for sample in samples {
+            self.file.write_all(&((sample * i16::MAX as f32) as i16).to_le_bytes())
+                .map_err(|error| format!("unable to write {}: {}", self.path.display(), error))?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<PathBuf, String> {
+        self.file.seek(SeekFrom::Start(0))
+            .and_then(|_| self.write_header())
+            .and_then(|_| self.file.flush())
+            .map_err(|error| format!("unable to write {}: {}", self.path.display(), error))?;
+        Ok(self.path)
+    }
+}
+
+// the samples waiting to be played, shared with rodio's thread. nothing's played from the queue
+// until it's built up to the target latency, and until then, or if it runs dry, the gap is
+// filled by a generator following the beeper so a late frame doesn't cut a beep short
+struct Playback {
+    samples: VecDeque<f32>,
+    primed: bool,
+    padding: Generator,
+}
+
+impl Playback {
+    fn new(tone: Tone) -> Self {
+        Self { samples: VecDeque::new(), primed: false, padding: Generator::new(tone) }
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        self.samples.extend(samples);
+        // the oldest go, so the delay doesn't grow without end
+        if self.samples.len() > MAX_LATENCY {
+            let excess = self.samples.len() - TARGET_LATENCY;
+            self.samples.drain(..excess);
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        if !self.primed && self.samples.len() >= TARGET_LATENCY {
+            self.primed = true;
+        }
+        let queued = if self.primed { self.samples.pop_front() } else { None };
+        match queued {
+            Some(sample) => sample,
+            None => {
+                self.primed = false;
+                self.padding.next_sample()
+            },
+        }
+    }
+}
+
+// what rodio plays, endlessly
+struct PlaybackSource(Arc<Mutex<Playback>>);
+
+impl Iterator for PlaybackSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        Some(self.0.lock().unwrap().next_sample())
+    }
+}
+
+impl Source for PlaybackSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Plays rendered samples through the default output device with rodio, a couple of frames
+/// behind so there's always something to play.
+pub struct RodioAudio {
+    _stream: OutputStream,
+    sink: Sink,
+    playback: Arc<Mutex<Playback>>,
+}
+
+impl RodioAudio {
+    pub fn new() -> Option<Self> {
+        let (stream, stream_handle) = OutputStream::try_default().ok()?;
+        let sink = Sink::try_new(&stream_handle).ok()?;
+        let playback = Arc::new(Mutex::new(Playback::new(Tone::default())));
+        sink.append(PlaybackSource(playback.clone()));
+        Some(Self {
+            _stream: stream,
+            sink,
+            playback,
+        })
+    }
+}
+
+impl AudioBackend for RodioAudio {
+    // the beep is already in the samples, this is only for filling gaps
+    fn set_beeping(&mut self, beeping: bool) {
+        self.playback.lock().unwrap().padding.gate().store(beeping, Ordering::Relaxed);
+    }
+
+    fn set_tone(&mut self, tone: Tone) {
+        self.playback.lock().unwrap().padding = Generator::new(tone);
+    }
+
+    fn play(&mut self, samples: &[f32]) {
+        self.playback.lock().unwrap().push(samples);
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.sink.set_volume(if muted { 0.0 } else { 1.0 });
+    }
+}
+
+/// Rodio if there's an output device, otherwise silence (e.g. over ssh).
+pub fn default_backend() -> Box<dyn AudioBackend> {
+    match RodioAudio::new() {
+        Some(audio) => Box::new(audio),
+        None => Box::new(NullAudio),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use super::{ AudioRenderer, Generator, Playback, SoundEvent, Tone, Waveform, MAX_LATENCY, TARGET_LATENCY };
+
+    #[test]
+    fn envelope_ramps() {
+        let tone = Tone {
+            waveform: Waveform::Square,
+            volume: 0.5,
+            attack: Duration::from_millis(1),
+            release: Duration::from_millis(2),
+            ..Tone::default()
+        };
+        let mut generator = Generator::new(tone);
+        assert_eq!(generator.next_sample(), 0.0);
+
+        // 44 samples to fade in, then the square wave at full volume
+        generator.gate().store(true, Ordering::Relaxed);
+        let attack: Vec<f32> = generator.by_ref().take(44).collect();
+        assert!(attack.windows(2).all(|pair| pair[1] > pair[0]));
+        assert_eq!(generator.next_sample().abs(), 0.5);
+
+        // and twice as long to fade out again, never jumping more than a step
+        generator.gate().store(false, Ordering::Relaxed);
+        let release: Vec<f32> = generator.by_ref().take(89).map(f32::abs).collect();
+        assert!(release.windows(2).all(|pair| pair[0] > pair[1] && pair[0] - pair[1] <= 0.5 / 88.0));
+        assert_eq!(generator.next_sample(), 0.0);
+
+        generator.gate().store(true, Ordering::Relaxed);
+        generator.muted().store(true, Ordering::Relaxed);
+        assert!(generator.take(100).all(|sample| sample == 0.0));
+    }
+
+    #[test]
+    fn tone_options() {
+        assert_eq!(Waveform::parse("triangle"), Ok(Waveform::Triangle));
+        assert!(Tone { volume: 2.0, ..Tone::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn renders_events_at_their_sample() {
+        let tone = Tone { waveform: Waveform::Square, attack: Duration::ZERO, release: Duration::ZERO, ..Tone::default() };
+        let mut renderer = AudioRenderer::new(tone);
+        // on 10ms in, off again at 15ms, which is after the first render
+        renderer.push(SoundEvent { time: Duration::from_millis(10), on: true });
+        renderer.push(SoundEvent { time: Duration::from_millis(15), on: false });
+
+        let samples = renderer.render(Duration::from_millis(12));
+        assert_eq!(samples.len(), 529);
+        assert!(samples[..441].iter().all(|sample| *sample == 0.0));
+        assert!(samples[441..].iter().all(|sample| *sample != 0.0));
+
+        let samples = renderer.render(Duration::from_millis(20));
+        assert_eq!(samples.len(), 882 - 529);
+        assert!(samples[..661 - 529].iter().all(|sample| *sample != 0.0));
+        assert!(samples[661 - 529..].iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn playback_keeps_its_latency() {
+        let mut playback = Playback::new(Tone { waveform: Waveform::Square, attack: Duration::ZERO, ..Tone::default() });
+
+        // nothing comes out of the queue until there's enough of it
+        playback.push(&[1.0; 100]);
+        assert_eq!(playback.next_sample(), 0.0);
+        playback.push(&[1.0; TARGET_LATENCY]);
+        assert!((0..TARGET_LATENCY + 100).all(|_| playback.next_sample() == 1.0));
+
+        // run dry mid-beep, the generator carries on with it
+        playback.padding.gate().store(true, Ordering::Relaxed);
+        assert_eq!(playback.next_sample().abs(), Tone::default().volume);
+
+        // and too far ahead, it's cut back to the target
+        playback.push(&[0.5; MAX_LATENCY]);
+        playback.push(&[1.0; 10]);
+        assert_eq!(playback.samples.len(), TARGET_LATENCY);
+        assert_eq!(playback.samples.back(), Some(&1.0));
+    }
+}
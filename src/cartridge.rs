@@ -0,0 +1,169 @@
+use gif::{ ColorOutput, DecodeOptions };
+use serde::Deserialize;
+
+use crate::palette::Palette;
+use crate::rom;
+use crate::vm::Quirks;
+
+/// The settings an Octo program was saved with.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Options {
+    pub quirks: Quirks,
+    pub tickrate: Option<u32>, // instructions per frame
+    pub palette: Option<Palette>,
+}
+
+impl Options {
+    /// The tick rate as instructions per second, see `rom::ticks_per_second`.
+    pub fn ticks_per_second(&self) -> Option<u64> {
+        rom::ticks_per_second(self.tickrate)
+    }
+}
+
+/// An Octo program unpacked from a cartridge.
+pub struct Cartridge {
+    pub program: String, // octo source
+    pub options: Options,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    program: String,
+    #[serde(default)]
+    options: CartridgeOptions,
+}
+
+// the options as octo stores them. anything we can't use (touch modes, rotation...) is ignored
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CartridgeOptions {
+    tickrate: Option<u32>,
+    background_color: Option<String>,
+    fill_color: Option<String>,
+    fill_color2: Option<String>,
+    blend_color: Option<String>,
+    shift_quirks: Option<bool>,
+    load_store_quirks: Option<bool>,
+    clip_quirks: Option<bool>,
+    v_blank_quirks: Option<bool>,
+    jump_quirks: Option<bool>,
+    logic_quirks: Option<bool>,
+}
+
+impl CartridgeOptions {
+    fn resolve(&self) -> Options {
+        let mut quirks = Quirks::default();
+        let fields = [
+            (self.shift_quirks, &mut quirks.shift),
+            (self.load_store_quirks, &mut quirks.memory_leave_i_unchanged),
+            (self.clip_quirks.map(|clip| !clip), &mut quirks.wrap),
+            (self.v_blank_quirks, &mut quirks.vblank),
+            (self.jump_quirks, &mut quirks.jump),
+            (self.logic_quirks, &mut quirks.logic),
+        ];
+        for (value, quirk) in fields {
+            if let Some(value) = value {
+                *quirk = value;
+            }
+        }
+
+        // the second plane's colour and the colour where both planes overlap come with xo-chip
+        let palette = match (&self.background_color, &self.fill_color, &self.fill_color2, &self.blend_color) {
+            (Some(background), Some(fill), Some(fill2), Some(blend)) => Palette::parse(&[background, fill, fill2, blend]).ok(),
+            (Some(background), Some(fill), ..) => Palette::parse(&[background, fill]).ok(),
+            _ => None,
+        };
+
+        Options {
+            quirks,
+            tickrate: self.tickrate,
+            palette,
+        }
+    }
+}
+
+pub fn is_cartridge(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")
+}
+
+/// Reads the program out of an Octo cartridge gif. The payload is hidden in the low two bits of
+/// every pixel's colour index, frame after frame, four pixels to a byte with the high bits
+/// first: a 4 byte big endian length and then that many bytes of json holding the program's
+/// source and options.
+pub fn load(bytes: &[u8]) -> Result<Cartridge, String> {
+    let mut options = DecodeOptions::new();
+    options.set_color_output(ColorOutput::Indexed);
+    let mut decoder = options.read_info(bytes).map_err(|error| format!("invalid cartridge: {}", error))?;
+
+    let mut payload = vec![];
+    let mut byte = 0;
+    let mut pairs = 0;
+    while let Some(frame) = decoder.read_next_frame().map_err(|error| format!("invalid cartridge: {}", error))? {
+        for index in frame.buffer.iter() {
+            byte = byte << 2 | index & 3;
+            pairs += 1;
+            if pairs == 4 {
+                payload.push(byte);
+                byte = 0;
+                pairs = 0;
+            }
+        }
+    }
+
+    let length = match payload.get(..4) {
+        Some(length) => u32::from_be_bytes(length.try_into().unwrap()) as usize,
+        None => return Err("cartridge is empty".to_string()),
+    };
+    let json = payload.get(4..4 + length).ok_or("cartridge is truncated")?;
+    let payload: Payload = serde_json::from_slice(json).map_err(|error| format!("invalid cartridge payload: {}", error))?;
+
+    Ok(Cartridge {
+        program: payload.program,
+        options: payload.options.resolve(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use gif::{ Encoder, Frame };
+
+    use super::{ load, is_cartridge };
+    use crate::palette::Palette;
+
+    #[test]
+    fn reads_payload() {
+        let json = br##"{
+            "program": ": main jump main",
+            "options": { "tickrate": 15, "fillColor": "#FFCC00", "backgroundColor": "#996600", "clipQuirks": true, "shiftQuirks": true }
+        }"##;
+        let mut payload = (json.len() as u32).to_be_bytes().to_vec();
+        payload.extend_from_slice(json);
+
+        // two frames, with the colour bits above the payload set to show they're ignored
+        let mut indices = vec![];
+        for byte in payload {
+            for shift in [6, 4, 2, 0] {
+                indices.push(0x04 | byte >> shift & 3);
+            }
+        }
+        let (width, height) = (32, (indices.len() / 32 + 2) / 2);
+        indices.resize(width * height * 2, 0);
+
+        let mut gif = vec![];
+        {
+            let palette: Vec<u8> = (0..8).flat_map(|index| [index * 32, 0, 0]).collect();
+            let mut encoder = Encoder::new(&mut gif, width as u16, height as u16, &palette).unwrap();
+            for pixels in indices.chunks(width * height) {
+                encoder.write_frame(&Frame::from_indexed_pixels(width as u16, height as u16, pixels, None)).unwrap();
+            }
+        }
+
+        assert!(is_cartridge(&gif));
+        let cartridge = load(&gif).unwrap();
+        assert_eq!(cartridge.program, ": main jump main");
+        assert_eq!(cartridge.options.ticks_per_second(), Some(900));
+        assert!(cartridge.options.quirks.shift);
+        assert!(!cartridge.options.quirks.wrap);
+        assert_eq!(cartridge.options.palette, Some(Palette::parse(&["#996600", "#FFCC00"]).unwrap()));
+    }
+}
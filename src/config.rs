@@ -0,0 +1,417 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+use sdl2::controller::{ Axis, Button };
+use sdl2::keyboard::Scancode;
+use serde::{ Deserialize, Serialize };
+
+use crate::audio::{ Tone, Waveform };
+use crate::filters::Filter;
+use crate::io::ScaleMode;
+use crate::keymap::{ KeyMap, KeyBindings };
+use crate::osd::OsdMode;
+use crate::palette::Palette;
+use crate::phosphor::AntiFlicker;
+use crate::romdb::{ self, ActionKeys };
+
+pub const DEFAULT_CONFIG_PATH: &str = "chip-8-rs.toml";
+
+/// Settings read from the config file, e.g.
+///
+/// ```toml
+/// [keys]
+/// 5 = ["W", "Up"]
+/// 8 = ["S", "Down"]
+///
+/// [controller]
+/// 6 = ["a", "rightshoulder"]
+///
+/// # a checkout of https://github.com/chip-8/chip-8-database
+/// database = "chip-8-database/database"
+///
+/// # what the launcher lists when no rom is given
+/// rom_dir = "roms"
+///
+/// # where F12 saves screenshots and F9 saves recordings
+/// screenshot_dir = "screenshots"
+///
+/// # one of classic, amber, green, octo or lcd, or colours of your own
+/// theme = "amber"
+///
+/// # off, or (show pixels lit in either of the last two frames) or decay:<rate>
+/// anti_flicker = "decay:0.6"
+///
+/// # how the screen fits the window: fit, integer or stretch
+/// scale = "integer"
+/// fullscreen = true
+///
+/// # post-processing: scanlines, grid, bloom and curvature, each with an optional :<strength>
+/// filters = ["scanlines:0.4", "bloom"]
+///
+/// # what the overlay shows besides notifications: off, stats or debug (F4 cycles them)
+/// osd = "stats"
+///
+/// [sound]
+/// waveform = "square" # square, sine or triangle
+/// frequency = 440 # hz
+/// volume = 0.3 # 0 to 1
+/// attack = 5 # milliseconds to fade in and out
+/// release = 10
+///
+/// [roms."pong.ch8"]
+/// colors = ["#000000", "#33FF66"]
+///
+/// [roms."pong.ch8".keys]
+/// 1 = ["Up"]
+/// 4 = ["Down"]
+///
+/// [roms."pong.ch8".controller]
+/// 1 = ["dpup", "lefty-"]
+/// 4 = ["dpdown", "lefty+"]
+/// ```
+///
+/// Anything left out keeps its default, and per-rom tables (keyed by the rom's file name) are
+/// laid over the top level ones. `colors` lists 2, 4 or 16 colours, background first, and takes
+/// precedence over `theme`. Keys use sdl's scancode names ("W", "Up", "Keypad 8", "Space"...).
+/// The window matches them by where the key is on the keyboard, whatever the layout, but the
+/// terminal by the character typed, so on an AZERTY or Dvorak layout the same bindings are on
+/// different keys in each. Controller buttons and axes use sdl's game controller names, with
+/// axes given a direction: `leftx-`, `righttrigger+` and so on.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rom_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub screenshot_dir: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub colors: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anti_flicker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fullscreen: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub osd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sound: Option<SoundConfig>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub keys: KeyBindings,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub controller: KeyBindings,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub roms: BTreeMap<String, RomConfig>,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RomConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub colors: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub keys: KeyBindings,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub controller: KeyBindings,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoundConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub waveform: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attack: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release: Option<u64>,
+}
+
+impl Config {
+    /// Reads the config at `path`. A missing file just means the defaults.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(error) => return Err(format!("unable to read {}: {}", path.display(), error)),
+        };
+        let config: Self = toml::from_str(&text).map_err(|error| format!("invalid config {}: {}", path.display(), error))?;
+
+        // catch bad key names now rather than when the rom starts
+        let actions = ActionKeys::new();
+        config.key_map(None, &actions)?;
+        config.controller_map(None, &actions)?;
+        config.palette(None)?;
+        config.anti_flicker()?;
+        config.scale_mode()?;
+        config.filters()?;
+        config.osd_mode()?;
+        config.tone()?;
+        for rom in config.roms.keys() {
+            config.key_map(Some(rom), &actions)?;
+            config.controller_map(Some(rom), &actions)?;
+            config.palette(Some(rom))?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string_pretty(self).map_err(|error| error.to_string())?;
+        fs::write(path, text).map_err(|error| format!("unable to write {}: {}", path.display(), error))
+    }
+
+    /// The default keyboard layout with the rom database's actions, then the config's bindings and
+    /// then the rom's, applied over it.
+    pub fn key_map(&self, rom: Option<&str>, actions: &ActionKeys) -> Result<KeyMap, String> {
+        let mut map = KeyMap::default();
+        romdb::bind_keyboard_actions(&mut map, actions);
+        check_key_names(&self.keys)?;
+        map.apply(&self.keys)?;
+        if let Some(rom_config) = rom.and_then(|rom| self.roms.get(rom)) {
+            check_key_names(&rom_config.keys)?;
+            map.apply(&rom_config.keys)?;
+        }
+        Ok(map)
+    }
+
+    /// The palette picked for the rom, if there is one.
+    pub fn palette(&self, rom: Option<&str>) -> Result<Option<Palette>, String> {
+        let rom_config = rom.and_then(|rom| self.roms.get(rom));
+        let tables = [rom_config.map(|rom| (&rom.colors, &rom.theme)), Some((&self.colors, &self.theme))];
+        for (colors, theme) in tables.into_iter().flatten() {
+            if !colors.is_empty() {
+                return Palette::parse(colors).map(Some);
+            }
+            if let Some(theme) = theme {
+                return Palette::theme(theme).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn anti_flicker(&self) -> Result<Option<AntiFlicker>, String> {
+        self.anti_flicker.as_deref().map(AntiFlicker::parse).transpose()
+    }
+
+    pub fn scale_mode(&self) -> Result<Option<ScaleMode>, String> {
+        self.scale.as_deref().map(ScaleMode::parse).transpose()
+    }
+
+    pub fn filters(&self) -> Result<Vec<Filter>, String> {
+        self.filters.iter().map(|filter| Filter::parse(filter)).collect()
+    }
+
+    pub fn osd_mode(&self) -> Result<Option<OsdMode>, String> {
+        self.osd.as_deref().map(OsdMode::parse).transpose()
+    }
+
+    /// The default tone with anything set in the `[sound]` table laid over it.
+    pub fn tone(&self) -> Result<Tone, String> {
+        let mut tone = Tone::default();
+        if let Some(sound) = &self.sound {
+            if let Some(waveform) = &sound.waveform {
+                tone.waveform = Waveform::parse(waveform)?;
+            }
+            tone.frequency = sound.frequency.unwrap_or(tone.frequency);
+            tone.volume = sound.volume.unwrap_or(tone.volume);
+            tone.attack = sound.attack.map_or(tone.attack, Duration::from_millis);
+            tone.release = sound.release.map_or(tone.release, Duration::from_millis);
+        }
+        tone.validate()?;
+        Ok(tone)
+    }
+
+    /// Same as `key_map`, for game controllers.
+    pub fn controller_map(&self, rom: Option<&str>, actions: &ActionKeys) -> Result<KeyMap, String> {
+        let mut map = KeyMap::controller();
+        romdb::bind_controller_actions(&mut map, actions);
+        check_controller_names(&self.controller)?;
+        map.apply(&self.controller)?;
+        if let Some(rom_config) = rom.and_then(|rom| self.roms.get(rom)) {
+            check_controller_names(&rom_config.controller)?;
+            map.apply(&rom_config.controller)?;
+        }
+        Ok(map)
+    }
+}
+
+// sdl would otherwise just never see a misspelled key pressed
+fn check_key_names(bindings: &KeyBindings) -> Result<(), String> {
+    for host in bindings.values().flatten() {
+        if Scancode::from_name(host).is_none() {
+            return Err(format!("{:?} is not a key name, expected one like \"W\", \"Up\" or \"Keypad 8\"", host));
+        }
+    }
+    Ok(())
+}
+
+// same for controllers, where an axis also needs a direction
+fn check_controller_names(bindings: &KeyBindings) -> Result<(), String> {
+    for host in bindings.values().flatten() {
+        let axis = host.strip_suffix(['+', '-']).and_then(Axis::from_string);
+        if Button::from_string(host).is_none() && axis.is_none() {
+            return Err(format!("{:?} is not a controller button or axis, expected one like \"a\", \"dpup\" or \"leftx-\"", host));
+        }
+    }
+    Ok(())
+}
+
+/// A loaded config along with where it came from and the rom being played, so that a frontend
+/// can write changes made in the app back to it.
+#[derive(Clone)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    pub config: Config,
+    pub rom: String,
+    pub actions: ActionKeys, // the rom's game actions from the rom database, if it's in there
+}
+
+impl ConfigFile {
+    pub fn load(path: PathBuf, rom: String) -> Result<Self, String> {
+        Ok(Self {
+            config: Config::load(&path)?,
+            path,
+            rom,
+            actions: ActionKeys::new(),
+        })
+    }
+
+    pub fn key_map(&self) -> KeyMap {
+        // already checked when loading
+        self.config.key_map(Some(&self.rom), &self.actions).unwrap()
+    }
+
+    pub fn controller_map(&self) -> KeyMap {
+        self.config.controller_map(Some(&self.rom), &self.actions).unwrap()
+    }
+
+    pub fn palette(&self) -> Option<Palette> {
+        self.config.palette(Some(&self.rom)).unwrap()
+    }
+
+    pub fn anti_flicker(&self) -> Option<AntiFlicker> {
+        self.config.anti_flicker().unwrap()
+    }
+
+    pub fn scale_mode(&self) -> Option<ScaleMode> {
+        self.config.scale_mode().unwrap()
+    }
+
+    pub fn filters(&self) -> Vec<Filter> {
+        self.config.filters().unwrap()
+    }
+
+    pub fn osd_mode(&self) -> Option<OsdMode> {
+        self.config.osd_mode().unwrap()
+    }
+
+    pub fn tone(&self) -> Tone {
+        self.config.tone().unwrap()
+    }
+
+    /// Stores a rebound key map: into the rom's own table if it has one, otherwise as the
+    /// top level bindings.
+    pub fn save_key_map(&mut self, map: &KeyMap) -> Result<(), String> {
+        match self.config.roms.get_mut(&self.rom) {
+            Some(rom_config) if !rom_config.keys.is_empty() => rom_config.keys = map.bindings(),
+            _ => self.config.keys = map.bindings(),
+        }
+        self.config.save(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::romdb::ActionKeys;
+
+    #[test]
+    fn rom_keys_override_top_level() {
+        let config: Config = toml::from_str(r#"
+            [keys]
+            5 = ["W", "Up"]
+
+            [roms."pong.ch8".keys]
+            1 = ["Up"]
+        "#).unwrap();
+
+        let map = config.key_map(None, &ActionKeys::new()).unwrap();
+        assert_eq!(map.keys_for("Up"), 1 << 0x5);
+        assert_eq!(map.keys_for("1"), 1 << 0x1);
+
+        let map = config.key_map(Some("pong.ch8"), &ActionKeys::new()).unwrap();
+        assert_eq!(map.keys_for("Up"), 1 << 0x1 | 1 << 0x5);
+        assert_eq!(map.keys_for("1"), 0);
+    }
+
+    #[test]
+    fn misspelled_keys() {
+        let config: Config = toml::from_str(r#"
+            [keys]
+            5 = ["w", "Keypad 8"]
+
+            [roms."pong.ch8".keys]
+            1 = ["Upp"]
+        "#).unwrap();
+
+        assert!(config.key_map(None, &ActionKeys::new()).is_ok());
+        assert!(config.key_map(Some("pong.ch8"), &ActionKeys::new()).is_err());
+    }
+
+    #[test]
+    fn misspelled_controller_names() {
+        let config: Config = toml::from_str(r#"
+            [controller]
+            5 = ["dpup", "lefty-"]
+
+            [roms."pong.ch8".controller]
+            1 = ["dpupp"]
+
+            [roms."brix.ch8".controller]
+            4 = ["lefty"]
+        "#).unwrap();
+
+        assert!(config.controller_map(None, &ActionKeys::new()).is_ok());
+        assert!(config.controller_map(Some("pong.ch8"), &ActionKeys::new()).is_err());
+        assert!(config.controller_map(Some("brix.ch8"), &ActionKeys::new()).is_err());
+    }
+
+    #[test]
+    fn rom_colors_override_theme() {
+        let config: Config = toml::from_str(r##"
+            theme = "amber"
+
+            [roms."pong.ch8"]
+            colors = ["#000000", "#33FF66"]
+        "##).unwrap();
+
+        assert_eq!(config.palette(None).unwrap(), Some(crate::palette::Palette::theme("amber").unwrap()));
+        assert_eq!(config.palette(Some("pong.ch8")).unwrap().unwrap().foreground(), [0x33, 0xFF, 0x66]);
+        assert_eq!(Config::default().palette(None).unwrap(), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = Config {
+            keys: crate::keymap::KeyMap::default().bindings(),
+            ..Config::default()
+        };
+        let text = toml::to_string_pretty(&config).unwrap();
+        assert_eq!(toml::from_str::<Config>(&text).unwrap(), config);
+    }
+}
@@ -0,0 +1,128 @@
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::{ Axis, Button, GameController };
+use sdl2::event::Event;
+
+use crate::keymap::KeyMap;
+
+// how far a stick or trigger has to move, out of 32767, before it counts as a press
+const AXIS_THRESHOLD: i16 = 16384;
+
+const BUTTONS: [Button; 21] = [
+    Button::A, Button::B, Button::X, Button::Y,
+    Button::Back, Button::Guide, Button::Start,
+    Button::LeftStick, Button::RightStick, Button::LeftShoulder, Button::RightShoulder,
+    Button::DPadUp, Button::DPadDown, Button::DPadLeft, Button::DPadRight,
+    Button::Misc1, Button::Paddle1, Button::Paddle2, Button::Paddle3, Button::Paddle4,
+    Button::Touchpad,
+];
+
+const AXES: [Axis; 6] = [
+    Axis::LeftX, Axis::LeftY, Axis::RightX, Axis::RightY,
+    Axis::TriggerLeft, Axis::TriggerRight,
+];
+
+/// Every game controller plugged in, read through a key map whose host keys are sdl's button
+/// names ("a", "dpup"...) and axis names with a direction ("leftx-", "righttrigger+").
+/// Controllers come and go as sdl reports them through `handle_event`.
+pub struct Controllers {
+    subsystem: GameControllerSubsystem,
+    open: Vec<GameController>,
+    map: KeyMap,
+}
+
+impl Controllers {
+    pub fn new(subsystem: GameControllerSubsystem, map: KeyMap) -> Self {
+        Self {
+            subsystem,
+            open: vec![],
+            map,
+        }
+    }
+
+    pub fn set_map(&mut self, map: KeyMap) {
+        self.map = map;
+    }
+
+    /// Opens and closes controllers as they're plugged in and out. sdl also reports the ones
+    /// already connected at startup this way.
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => match self.subsystem.open(which) {
+                Ok(controller) => {
+                    println!("Controller connected: {}", controller.name());
+                    self.open.push(controller);
+                },
+                Err(error) => eprintln!("unable to open controller {}: {}", which, error),
+            },
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.open.retain(|controller| controller.instance_id() != which);
+            },
+            _ => {},
+        }
+    }
+
+    /// The chip-8 keys held across every open controller.
+    pub fn keys(&self) -> u16 {
+        let mut keys = 0;
+        for controller in &self.open {
+            for button in BUTTONS {
+                if controller.button(button) {
+                    keys |= self.map.keys_for(&button.string());
+                }
+            }
+            for axis in AXES {
+                let value = controller.axis(axis);
+                if value <= -AXIS_THRESHOLD {
+                    keys |= self.map.keys_for(&format!("{}-", axis.string()));
+                } else if value >= AXIS_THRESHOLD {
+                    keys |= self.map.keys_for(&format!("{}+", axis.string()));
+                }
+            }
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::sys;
+
+    use super::Controllers;
+    use crate::keymap::KeyMap;
+
+    // plugs in one of sdl's virtual controllers, which map their buttons and axes in the same
+    // order as the game controller api, so no hardware is needed
+    #[test]
+    fn virtual_controller() {
+        let sdl_context = sdl2::init().unwrap();
+        let subsystem = sdl_context.game_controller().unwrap();
+        let mut event_pump = sdl_context.event_pump().unwrap();
+        let mut controllers = Controllers::new(subsystem, KeyMap::controller());
+
+        let device = unsafe {
+            sys::SDL_JoystickAttachVirtual(sys::SDL_JoystickType::SDL_JOYSTICK_TYPE_GAMECONTROLLER, 6, 15, 0)
+        };
+        assert!(device >= 0, "{}", sdl2::get_error());
+
+        let pump = |event_pump: &mut sdl2::EventPump, controllers: &mut Controllers| {
+            for event in event_pump.poll_iter() {
+                controllers.handle_event(&event);
+            }
+        };
+        pump(&mut event_pump, &mut controllers);
+        assert_eq!(controllers.open.len(), 1);
+        assert_eq!(controllers.keys(), 0);
+
+        let joystick = unsafe { sys::SDL_JoystickFromInstanceID(controllers.open[0].instance_id() as i32) };
+        unsafe {
+            sys::SDL_JoystickSetVirtualButton(joystick, sys::SDL_GameControllerButton::SDL_CONTROLLER_BUTTON_A as i32, 1);
+            sys::SDL_JoystickSetVirtualAxis(joystick, sys::SDL_GameControllerAxis::SDL_CONTROLLER_AXIS_LEFTY as i32, -32768);
+        }
+        pump(&mut event_pump, &mut controllers);
+        assert_eq!(controllers.keys(), 1 << 0x6 | 1 << 0x5);
+
+        unsafe { sys::SDL_JoystickDetachVirtual(device) };
+        pump(&mut event_pump, &mut controllers);
+        assert!(controllers.open.is_empty());
+    }
+}
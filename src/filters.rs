@@ -0,0 +1,279 @@
+use crate::palette::{ Palette, Rgb };
+use crate::phosphor::Levels;
+use crate::vm::{ Screen, SCREEN_WIDTH, SCREEN_HEIGHT };
+
+// how far the screen is scaled up before filtering, so the filters have pixels to work with
+pub const FILTER_SCALE: usize = 8;
+
+/// An rgb image, three bytes to a pixel, row by row. `scale` is how many image pixels wide each
+/// chip-8 pixel is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub scale: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Draws the screen in the palette's colours, each chip-8 pixel a `scale` sized square.
+    pub fn render(levels: &Levels, palette: &Palette, scale: usize) -> Self {
+        let (width, height) = (SCREEN_WIDTH * scale, SCREEN_HEIGHT * scale);
+        let mut image = Self { width, height, scale, pixels: vec![0; width * height * 3] };
+        let off = palette.background();
+        let on = palette.foreground();
+
+        for y in 0..height {
+            for x in 0..width {
+                let level = levels[y / scale][x / scale];
+                let color = [0, 1, 2].map(|channel| {
+                    let (off, on) = (off[channel] as f32, on[channel] as f32);
+                    (off + (on - off) * level) as u8
+                });
+                image.set(x, y, color);
+            }
+        }
+        image
+    }
+
+    /// Same as `render`, for a screen of pixels that are just on or off.
+    pub fn from_screen(screen: &Screen, palette: &Palette, scale: usize) -> Self {
+        let mut levels = [[0.0; SCREEN_WIDTH]; SCREEN_HEIGHT];
+        for (levels, row) in levels.iter_mut().zip(screen.iter()) {
+            for (level, pixel) in levels.iter_mut().zip(row.iter()) {
+                *level = *pixel as u8 as f32;
+            }
+        }
+        Self::render(&levels, palette, scale)
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Rgb {
+        let offset = (y * self.width + x) * 3;
+        [self.pixels[offset], self.pixels[offset + 1], self.pixels[offset + 2]]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: Rgb) {
+        let offset = (y * self.width + x) * 3;
+        self.pixels[offset..offset + 3].copy_from_slice(&color);
+    }
+
+    fn darken(&mut self, x: usize, y: usize, amount: f32) {
+        let color = self.get(x, y).map(|channel| (channel as f32 * (1.0 - amount)) as u8);
+        self.set(x, y, color);
+    }
+}
+
+/// Post-processing run over the scaled up screen. Each takes a strength from 0 to 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Scanlines(f32), // darkens the lower half of every row of chip-8 pixels
+    Grid(f32), // darkens the edges between chip-8 pixels
+    Bloom(f32), // bleeds light from lit pixels into their surroundings
+    Curvature(f32), // bulges the screen out like the glass of a crt
+}
+
+impl Filter {
+    /// Parses `scanlines`, `grid`, `bloom` or `curvature`, optionally with `:<strength>`.
+    pub fn parse(filter: &str) -> Result<Self, String> {
+        let (name, strength) = match filter.split_once(':') {
+            Some((name, strength)) => match strength.parse::<f32>() {
+                Ok(strength) if (0.0..=1.0).contains(&strength) => (name, Some(strength)),
+                _ => return Err(format!("invalid strength {}, expected a number from 0 to 1", strength)),
+            },
+            None => (filter, None),
+        };
+        match name {
+            "scanlines" => Ok(Self::Scanlines(strength.unwrap_or(0.5))),
+            "grid" => Ok(Self::Grid(strength.unwrap_or(0.5))),
+            "bloom" => Ok(Self::Bloom(strength.unwrap_or(0.5))),
+            "curvature" => Ok(Self::Curvature(strength.unwrap_or(0.2))),
+            _ => Err(format!("unknown filter {}, expected scanlines, grid, bloom or curvature", name)),
+        }
+    }
+
+    pub fn apply(&self, image: &mut Image) {
+        match *self {
+            Filter::Scanlines(strength) => Self::scanlines(image, strength),
+            Filter::Grid(strength) => Self::grid(image, strength),
+            Filter::Bloom(strength) => Self::bloom(image, strength),
+            Filter::Curvature(strength) => Self::curvature(image, strength),
+        }
+    }
+
+    fn scanlines(image: &mut Image, strength: f32) {
+        for y in 0..image.height {
+            if (y % image.scale) * 2 >= image.scale {
+                for x in 0..image.width {
+                    image.darken(x, y, strength);
+                }
+            }
+        }
+    }
+
+    fn grid(image: &mut Image, strength: f32) {
+        for y in 0..image.height {
+            for x in 0..image.width {
+                if x % image.scale == 0 || y % image.scale == 0 {
+                    image.darken(x, y, strength);
+                }
+            }
+        }
+    }
+
+    // adds a blurred copy of the image over itself, spreading light about one chip-8 pixel
+    fn bloom(image: &mut Image, strength: f32) {
+        let radius = image.scale.max(1);
+        let glow = Self::box_blur_rows(&Self::transpose(&Self::box_blur_rows(&Self::transpose(image), radius)), radius);
+
+        for (pixel, glow) in image.pixels.iter_mut().zip(glow.pixels.iter()) {
+            *pixel = (*pixel as f32 + *glow as f32 * strength).min(255.0) as u8;
+        }
+    }
+
+    fn transpose(image: &Image) -> Image {
+        let mut transposed = Image { width: image.height, height: image.width, ..image.clone() };
+        for y in 0..image.height {
+            for x in 0..image.width {
+                transposed.set(y, x, image.get(x, y));
+            }
+        }
+        transposed
+    }
+
+    // averages each pixel with the `radius` pixels either side of it along its row
+    fn box_blur_rows(image: &Image, radius: usize) -> Image {
+        let mut blurred = image.clone();
+        let window = (radius * 2 + 1) as u32;
+        for y in 0..image.height {
+            let mut sums = [0u32; 3];
+            // the window starts hanging off the left edge, which counts as black
+            for x in 0..radius.min(image.width) {
+                for (sum, channel) in sums.iter_mut().zip(image.get(x, y)) {
+                    *sum += channel as u32;
+                }
+            }
+            for x in 0..image.width {
+                if x + radius < image.width {
+                    for (sum, channel) in sums.iter_mut().zip(image.get(x + radius, y)) {
+                        *sum += channel as u32;
+                    }
+                }
+                blurred.set(x, y, sums.map(|sum| (sum / window) as u8));
+                if x >= radius {
+                    for (sum, channel) in sums.iter_mut().zip(image.get(x - radius, y)) {
+                        *sum -= channel as u32;
+                    }
+                }
+            }
+        }
+        blurred
+    }
+
+    // barrel distortion: each pixel samples from further out the further it is from the centre,
+    // and anything that samples from off the screen is black
+    fn curvature(image: &mut Image, strength: f32) {
+        let source = image.clone();
+        let (half_width, half_height) = (image.width as f32 / 2.0, image.height as f32 / 2.0);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let u = (x as f32 + 0.5 - half_width) / half_width;
+                let v = (y as f32 + 0.5 - half_height) / half_height;
+                let bulge = 1.0 + strength * (u * u + v * v) / 2.0;
+                let (u, v) = (u * bulge, v * bulge);
+
+                let color = if u.abs() < 1.0 && v.abs() < 1.0 {
+                    let source_x = (u * half_width + half_width) as usize;
+                    let source_y = (v * half_height + half_height) as usize;
+                    source.get(source_x, source_y)
+                } else {
+                    [0, 0, 0]
+                };
+                image.set(x, y, color);
+            }
+        }
+    }
+}
+
+/// Renders the screen and runs the filters over it, or just renders it a pixel to a pixel when
+/// there aren't any.
+pub fn post_process(levels: &Levels, palette: &Palette, filters: &[Filter]) -> Image {
+    let scale = if filters.is_empty() { 1 } else { FILTER_SCALE };
+    let mut image = Image::render(levels, palette, scale);
+    for filter in filters {
+        filter.apply(&mut image);
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Filter, Image };
+    use crate::palette::Palette;
+
+    // a 4x4 image with one lit pixel per chip-8 pixel of scale 2, lit at the given positions
+    fn image(lit: &[(usize, usize)]) -> Image {
+        let mut image = Image { width: 4, height: 4, scale: 2, pixels: vec![0; 4 * 4 * 3] };
+        for (x, y) in lit {
+            image.set(*x, *y, [200, 200, 200]);
+        }
+        image
+    }
+
+    fn all(width: usize, height: usize) -> Vec<(usize, usize)> {
+        (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).collect()
+    }
+
+    #[test]
+    fn render() {
+        let mut levels = [[0.0; 64]; 32];
+        levels[0][1] = 1.0;
+        levels[0][2] = 0.5;
+        let image = Image::render(&levels, &Palette::default(), 2);
+        assert_eq!((image.width, image.height), (128, 64));
+        assert_eq!(image.get(0, 0), [0, 0, 0]);
+        assert_eq!(image.get(3, 1), [255, 255, 255]);
+        assert_eq!(image.get(4, 0), [127, 127, 127]);
+    }
+
+    #[test]
+    fn scanlines_and_grid() {
+        let mut scanlines = image(&all(4, 4));
+        Filter::Scanlines(0.5).apply(&mut scanlines);
+        assert_eq!(scanlines.get(0, 0), [200, 200, 200]);
+        assert_eq!(scanlines.get(0, 1), [100, 100, 100]);
+        assert_eq!(scanlines.get(3, 2), [200, 200, 200]);
+        assert_eq!(scanlines.get(3, 3), [100, 100, 100]);
+
+        let mut grid = image(&all(4, 4));
+        Filter::Grid(1.0).apply(&mut grid);
+        assert_eq!(grid.get(0, 1), [0, 0, 0]);
+        assert_eq!(grid.get(2, 3), [0, 0, 0]);
+        assert_eq!(grid.get(1, 1), [200, 200, 200]);
+        assert_eq!(grid.get(3, 3), [200, 200, 200]);
+    }
+
+    #[test]
+    fn bloom_spreads_light() {
+        let mut bloom = image(&[(1, 1)]);
+        Filter::Bloom(1.0).apply(&mut bloom);
+        assert!(bloom.get(1, 1)[0] > 200);
+        assert!(bloom.get(2, 1)[0] > 0);
+        assert!(bloom.get(1, 2)[0] > 0);
+        assert_eq!(bloom.get(3, 3), [8, 8, 8]);
+    }
+
+    #[test]
+    fn curvature_keeps_the_middle() {
+        let mut image = Image { width: 16, height: 16, scale: 2, pixels: vec![255; 16 * 16 * 3] };
+        Filter::Curvature(1.0).apply(&mut image);
+        assert_eq!(image.get(8, 8), [255, 255, 255]);
+        assert_eq!(image.get(0, 0), [0, 0, 0]);
+        assert_eq!(image.get(15, 15), [0, 0, 0]);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Filter::parse("bloom:0.3"), Ok(Filter::Bloom(0.3)));
+        assert!(Filter::parse("blur").is_err());
+    }
+}
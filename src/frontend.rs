@@ -0,0 +1,166 @@
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex };
+
+use crate::audio::Tone;
+use crate::filters::Image;
+use crate::palette::Palette;
+use crate::phosphor::AntiFlicker;
+use crate::romdb::ActionKeys;
+use crate::screenshot::ScreenshotMode;
+use crate::vm::Screen;
+
+/// Keys held during a frame and whether the user asked to quit, to switch to the next colour
+/// theme, to toggle fullscreen, recording or sound, for a screenshot, or to change how the vm
+/// runs. An input backend can also hand over a screen of its own, e.g. a menu, to be shown in
+/// place of the vm's.
+#[derive(Clone, Copy, Default)]
+pub struct Input {
+    pub keys: u16,
+    pub quit: bool,
+    pub next_theme: bool,
+    pub toggle_fullscreen: bool,
+    pub screenshot: Option<ScreenshotMode>,
+    pub toggle_recording: bool,
+    pub toggle_mute: bool,
+    pub toggle_pause: bool,
+    pub advance_frame: bool, // while paused
+    pub fast_forward: bool, // held
+    pub toggle_fast_forward: bool,
+    pub toggle_slow_motion: bool,
+    pub reset: bool,
+    pub cycle_osd: bool,
+    pub screen: Option<Screen>,
+}
+
+pub trait DisplayBackend {
+    fn draw(&mut self, screen: &Screen);
+
+    fn set_palette(&mut self, _palette: Palette) {}
+
+    fn set_anti_flicker(&mut self, _mode: AntiFlicker) {}
+
+    fn toggle_fullscreen(&mut self) {}
+
+    /// Shows what the emulator's doing, e.g. paused or fast forwarding.
+    fn set_title(&mut self, _title: &str) {}
+
+    /// Lines of text to show over the screen from the next frame on.
+    fn set_osd(&mut self, _lines: &[String]) {}
+
+    /// The last frame drawn, as the mode asks. Displays that can't capture it return `None` and
+    /// get a native screenshot instead.
+    fn capture(&mut self, _mode: ScreenshotMode) -> Option<Image> {
+        None
+    }
+}
+
+pub trait InputBackend {
+    fn poll(&mut self) -> Input;
+
+    /// A rom file dropped on the window since the last call, to be played instead.
+    fn take_dropped(&mut self) -> Option<PathBuf> {
+        None
+    }
+
+    /// The rom now running, by file name, and its game actions from the rom database, for
+    /// backends with per-rom key bindings.
+    fn set_rom(&mut self, _name: &str, _actions: &ActionKeys) {}
+}
+
+pub trait AudioBackend {
+    fn set_beeping(&mut self, beeping: bool);
+
+    /// Samples rendered from the vm's sound events since the last frame, for backends that play
+    /// them rather than following `set_beeping`.
+    fn play(&mut self, _samples: &[f32]) {}
+
+    /// What to beep with when `play` hasn't been given enough samples.
+    fn set_tone(&mut self, _tone: Tone) {}
+
+    fn set_muted(&mut self, _muted: bool) {}
+}
+
+/// Everything the io loop in `System` drives once per frame.
+pub struct Frontend {
+    pub display: Box<dyn DisplayBackend>,
+    pub input: Box<dyn InputBackend>,
+    pub audio: Box<dyn AudioBackend>,
+}
+
+impl Frontend {
+    /// A frontend that draws nothing, plays nothing and never quits.
+    pub fn null() -> Self {
+        Self {
+            display: Box::new(NullDisplay),
+            input: Box::new(NullInput),
+            audio: Box::new(NullAudio),
+        }
+    }
+}
+
+pub struct NullDisplay;
+
+impl DisplayBackend for NullDisplay {
+    fn draw(&mut self, _screen: &Screen) {}
+}
+
+pub struct NullInput;
+
+impl InputBackend for NullInput {
+    fn poll(&mut self) -> Input {
+        Input::default()
+    }
+}
+
+pub struct NullAudio;
+
+impl AudioBackend for NullAudio {
+    fn set_beeping(&mut self, _beeping: bool) {}
+}
+
+/// Keeps every frame it is asked to draw. Clones share the same frames, so a clone can be kept
+/// to inspect them after the frontend has moved onto the io thread.
+#[derive(Clone, Default)]
+pub struct RecordingDisplay {
+    pub frames: Arc<Mutex<Vec<Screen>>>,
+}
+
+impl DisplayBackend for RecordingDisplay {
+    fn draw(&mut self, screen: &Screen) {
+        self.frames.lock().unwrap().push(*screen);
+    }
+}
+
+/// Keeps the beeper state of every frame, shared between clones like `RecordingDisplay`.
+#[derive(Clone, Default)]
+pub struct RecordingAudio {
+    pub frames: Arc<Mutex<Vec<bool>>>,
+}
+
+impl AudioBackend for RecordingAudio {
+    fn set_beeping(&mut self, beeping: bool) {
+        self.frames.lock().unwrap().push(beeping);
+    }
+}
+
+/// Plays back one set of held keys per frame, then quits.
+pub struct ScriptedInput {
+    frames: std::vec::IntoIter<u16>,
+}
+
+impl ScriptedInput {
+    pub fn new(frames: Vec<u16>) -> Self {
+        Self {
+            frames: frames.into_iter(),
+        }
+    }
+}
+
+impl InputBackend for ScriptedInput {
+    fn poll(&mut self) -> Input {
+        match self.frames.next() {
+            Some(keys) => Input { keys, ..Input::default() },
+            None => Input { quit: true, ..Input::default() },
+        }
+    }
+}
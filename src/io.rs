@@ -1,25 +1,227 @@
 extern crate sdl2;
 
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
 use crate::vm::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::audio;
+use crate::config::ConfigFile;
+use crate::controller::Controllers;
+use crate::filters::{ self, Filter, Image, FILTER_SCALE };
+use crate::frontend::{ Frontend, DisplayBackend, InputBackend, Input };
+use crate::keymap::{ KeyMap, Rebinder };
+use crate::launcher::Launcher;
+use crate::osd;
+use crate::palette::Palette;
+use crate::phosphor::{ AntiFlicker, Phosphor };
+use crate::romdb::ActionKeys;
+use crate::screenshot::ScreenshotMode;
 
 use super::vm;
 
 use sdl2::{ EventPump };
-use sdl2::video::Window;
-use sdl2::render::Canvas;
-use sdl2::pixels::Color;
+use sdl2::event::Event;
+use sdl2::keyboard::Scancode;
+use sdl2::video::{ FullscreenType, Window };
+use sdl2::render::{ BlendMode, Canvas, Texture };
+use sdl2::pixels::{ Color, PixelFormatEnum };
 use sdl2::rect::Rect;
 
-const PIXEL_SCALING: u32 = 30;
-const RENDER_GRID: bool = false;
+// starting size of the window, which can then be resized
+const PIXEL_SCALING: u32 = 15;
+
+/// How the screen is fitted into the window.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ScaleMode {
+    #[default]
+    Fit, // as large as fits, keeping the 2:1 aspect ratio and letterboxing the rest
+    Integer, // the largest whole multiple of the screen size that fits, letterboxed
+    Stretch, // fills the window
+}
 
+impl ScaleMode {
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode {
+            "fit" => Ok(Self::Fit),
+            "integer" => Ok(Self::Integer),
+            "stretch" => Ok(Self::Stretch),
+            _ => Err(format!("unknown scale mode {}, expected fit, integer or stretch", mode)),
+        }
+    }
+
+    /// Where the screen goes in a window of the given size.
+    pub fn viewport(&self, window_width: u32, window_height: u32) -> Rect {
+        let (screen_width, screen_height) = (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        let (width, height) = match self {
+            ScaleMode::Stretch => (window_width, window_height),
+            ScaleMode::Fit => {
+                let scale = f32::min(window_width as f32 / screen_width as f32, window_height as f32 / screen_height as f32);
+                ((screen_width as f32 * scale) as u32, (screen_height as f32 * scale) as u32)
+            },
+            ScaleMode::Integer => {
+                // don't let the screen vanish when the window is smaller than it
+                let scale = u32::max(1, u32::min(window_width / screen_width, window_height / screen_height));
+                (screen_width * scale, screen_height * scale)
+            },
+        };
+        let x = (window_width as i32 - width as i32) / 2;
+        let y = (window_height as i32 - height as i32) / 2;
+        Rect::new(x, y, width.max(1), height.max(1))
+    }
+}
+
+/// Options for the sdl window.
+#[derive(Clone, Default)]
+pub struct WindowOptions {
+    pub scale: ScaleMode,
+    pub fullscreen: bool,
+    pub filters: Vec<Filter>,
+}
+
+/// Draws the screen into a resizable sdl window. Frames are written into a streaming texture the
+/// size of the chip-8 screen and scaled up by the renderer, or with filters on, scaled up and
+/// filtered on the cpu first.
 pub struct IO {
     canvas: Canvas<Window>,
+    texture: Texture,
+    scale: ScaleMode,
+    filters: Vec<Filter>,
+    osd: Vec<String>,
+    palette: Palette,
+    phosphor: Phosphor,
+}
+
+/// Keyboard and game controller input from the sdl window's event pump. F1 walks through
+/// rebinding the keyboard, and the new bindings are saved to the config file if there is one.
+/// A rom file dropped on the window is played in place of the current one.
+/// F4 cycles the overlay's readouts, F5 resets, F6 pauses, F7 advances a frame while paused, F8
+/// toggles slow motion and F10 fast forward, which Tab also does while it's held.
+pub struct SdlInput {
     pub event_pump: EventPump,
+    held: HashSet<Scancode>,
+    keys: KeyMap,
+    controllers: Option<Controllers>,
+    config: Option<ConfigFile>,
+    rebinder: Option<Rebinder>,
+    dropped: Option<PathBuf>,
+}
+
+/// Opens an sdl window and builds a frontend around it, with sound if there's an audio device.
+pub fn frontend(config: Option<ConfigFile>, window: WindowOptions) -> Frontend {
+    let (io, mut input) = IO::new(window);
+    if let Some(config) = config {
+        input.keys = config.key_map();
+        if let Some(controllers) = &mut input.controllers {
+            controllers.set_map(config.controller_map());
+        }
+        input.config = Some(config);
+    }
+    Frontend {
+        display: Box::new(io),
+        input: Box::new(input),
+        audio: audio::default_backend(),
+    }
+}
+
+// how big a pixel of the overlay's font is, for a screen or window this tall
+fn font_size(height: u32) -> i32 {
+    (height / 100).max(2) as i32
+}
+
+// a line of text in the overlay's font, with its top left at `left`, `top`
+fn draw_text(canvas: &mut Canvas<Window>, text: &str, left: i32, top: i32, size: i32, color: Color) {
+    let pixels: Vec<Rect> = osd::text_pixels(text)
+        .into_iter()
+        .map(|(x, y)| Rect::new(left + x as i32 * size, top + y as i32 * size, size as u32, size as u32))
+        .collect();
+    canvas.set_draw_color(color);
+    canvas.fill_rects(&pixels).unwrap();
+}
+
+/// Shows the launcher in a window of its own until a rom's picked, with return or by dropping
+/// a file on the window, or it's closed. Up, down, page up and page down move through the list
+/// and typing a letter jumps to the next title starting with it.
+pub fn launch(mut launcher: Launcher, fullscreen: bool) -> Option<PathBuf> {
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let window = video_subsystem
+        .window(
+            "chip-8-rs",
+            vm::SCREEN_WIDTH as u32 * PIXEL_SCALING,
+            vm::SCREEN_HEIGHT as u32 * PIXEL_SCALING
+        )
+        .position_centered()
+        .resizable()
+        .build()
+        .unwrap();
+    let mut canvas = window.into_canvas().build().unwrap();
+    if fullscreen {
+        canvas.window_mut().set_fullscreen(FullscreenType::Desktop).unwrap();
+    }
+    let mut event_pump = sdl_context.event_pump().unwrap();
+
+    loop {
+        let (_, height) = canvas.output_size().unwrap();
+        let size = font_size(height);
+        let line_height = (osd::GLYPH_HEIGHT as i32 + 2) * size;
+        // a line for the heading and a gap under it
+        let rows = (height as i32 / line_height - 3).max(1);
+
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => return None,
+                Event::DropFile { filename, .. } => return Some(PathBuf::from(filename)),
+                Event::KeyDown { scancode: Some(scancode), .. } => match scancode {
+                    Scancode::Escape => return None,
+                    Scancode::Return | Scancode::KpEnter => {
+                        if let Some(entry) = launcher.selected() {
+                            return Some(entry.path.clone());
+                        }
+                    },
+                    Scancode::Up => launcher.move_by(-1),
+                    Scancode::Down => launcher.move_by(1),
+                    Scancode::PageUp => launcher.move_by(-rows as isize),
+                    Scancode::PageDown => launcher.move_by(rows as isize),
+                    Scancode::Home => launcher.move_by(isize::MIN),
+                    Scancode::End => launcher.move_by(isize::MAX),
+                    _ => {
+                        let mut name = scancode.name().chars();
+                        if let (Some(letter), None) = (name.next(), name.next()) {
+                            launcher.jump(letter);
+                        }
+                    },
+                },
+                _ => {},
+            }
+        }
+
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.clear();
+        let left = line_height;
+        let heading = match launcher.selected() {
+            Some(_) => "Pick a rom, or drop one here",
+            None => "No roms found, drop one here",
+        };
+        draw_text(&mut canvas, heading, left, size * 2, size, Color::RGB(160, 160, 160));
+
+        let (lines, selected) = launcher.page(rows as usize);
+        for (row, line) in lines.iter().enumerate() {
+            let top = (row as i32 + 2) * line_height;
+            if row == selected && launcher.selected().is_some() {
+                canvas.set_draw_color(Color::RGB(60, 60, 60));
+                canvas.fill_rect(Rect::new(left - size, top - size, (osd::text_width(line) as i32 + 2) as u32 * size as u32, line_height as u32)).unwrap();
+            }
+            draw_text(&mut canvas, line, left, top, size, Color::RGB(255, 255, 255));
+        }
+        canvas.present();
+        thread::sleep(Duration::from_millis(16));
+    }
 }
 
 impl IO {
-    pub fn new() -> Self {
+    pub fn new(options: WindowOptions) -> (Self, SdlInput) {
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
@@ -30,58 +232,285 @@ impl IO {
                 vm::SCREEN_HEIGHT as u32 * PIXEL_SCALING
             )
             .position_centered()
+            .resizable()
             .build()
             .unwrap();
 
         let mut canvas = window.into_canvas().build().unwrap();
+        if options.fullscreen {
+            canvas.window_mut().set_fullscreen(FullscreenType::Desktop).unwrap();
+        }
+        let texture_scale = if options.filters.is_empty() { 1 } else { FILTER_SCALE as u32 };
+        let texture = canvas
+            .texture_creator()
+            .create_texture_streaming(PixelFormatEnum::RGB24, SCREEN_WIDTH as u32 * texture_scale, SCREEN_HEIGHT as u32 * texture_scale)
+            .unwrap();
 
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
         canvas.present();
         let event_pump = sdl_context.event_pump().unwrap();
 
-        Self {
-            canvas,
+        // carry on with just the keyboard if controllers aren't available
+        let controllers = match sdl_context.game_controller() {
+            Ok(subsystem) => Some(Controllers::new(subsystem, KeyMap::controller())),
+            Err(error) => {
+                eprintln!("no game controller support: {}", error);
+                None
+            },
+        };
+
+        let input = SdlInput {
             event_pump,
-        }
+            held: HashSet::new(),
+            keys: KeyMap::default(),
+            controllers,
+            config: None,
+            rebinder: None,
+            dropped: None,
+        };
+
+        let io = Self {
+            canvas,
+            texture,
+            scale: options.scale,
+            filters: options.filters,
+            osd: vec![],
+            palette: Palette::default(),
+            phosphor: Phosphor::new(AntiFlicker::Off),
+        };
+        (io, input)
     }
 
     pub fn draw_screen(&mut self, pixels: &[[bool; SCREEN_WIDTH]; SCREEN_HEIGHT]) {
+        // pixels, shaded between the background and foreground by how lit they are
+        let levels = self.phosphor.apply(pixels);
+        let image = filters::post_process(levels, &self.palette, &self.filters);
+        self.texture.update(None, &image.pixels, image.width * 3).unwrap();
+
         let c = &mut self.canvas;
-        let off = Color::RGB(0, 0, 0);
-        let on = Color::RGB(255, 255, 255);
-        let scale = PIXEL_SCALING as usize;
+        let (width, height) = c.output_size().unwrap();
+        let viewport = self.scale.viewport(width, height);
+
+        c.set_draw_color(Color::RGB(0, 0, 0));
+        c.clear();
+        c.copy(&self.texture, None, viewport).unwrap();
+        self.draw_osd(viewport);
+        self.canvas.present();
+    }
+
+    // the overlay's lines down the top left of the screen, on a dark backdrop so they can be read
+    // over anything. font pixels are sized to the screen, so text keeps its size relative to it
+    fn draw_osd(&mut self, viewport: Rect) {
+        if self.osd.is_empty() {
+            return;
+        }
+        let size = font_size(viewport.height());
+        let line_height = (osd::GLYPH_HEIGHT as i32 + 2) * size;
+        let c = &mut self.canvas;
+        c.set_blend_mode(BlendMode::Blend);
+
+        for (line, text) in self.osd.iter().enumerate() {
+            let left = viewport.x() + size;
+            let top = viewport.y() + size + line as i32 * line_height;
+            let width = (osd::text_width(text) as i32 + 2) * size;
+            c.set_draw_color(Color::RGBA(0, 0, 0, 160));
+            c.fill_rect(Rect::new(left, top, width as u32, line_height as u32)).unwrap();
+            draw_text(c, text, left + size, top + size, size, Color::RGBA(255, 255, 255, 230));
+        }
+        c.set_blend_mode(BlendMode::None);
+    }
+
+    /// The last frame drawn. Display screenshots are read back from the window at the size it's
+    /// shown, filters and all.
+    pub fn capture(&mut self, mode: ScreenshotMode) -> Result<Image, String> {
+        if mode == ScreenshotMode::Native {
+            return Ok(Image::render(self.phosphor.levels(), &self.palette, 1));
+        }
 
-        c.set_draw_color(off);
+        // what's been presented can't be read back, so draw the frame again without presenting it
+        let c = &mut self.canvas;
+        let (width, height) = c.output_size()?;
+        let viewport = self.scale.viewport(width, height);
+        c.set_draw_color(Color::RGB(0, 0, 0));
         c.clear();
+        c.copy(&self.texture, None, viewport)?;
+        let pixels = c.read_pixels(viewport, PixelFormatEnum::RGB24)?;
+
+        Ok(Image {
+            width: viewport.width() as usize,
+            height: viewport.height() as usize,
+            scale: viewport.width() as usize / SCREEN_WIDTH,
+            pixels,
+        })
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let fullscreen = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        if let Err(error) = window.set_fullscreen(fullscreen) {
+            eprintln!("unable to toggle fullscreen: {}", error);
+        }
+    }
+}
+
+impl DisplayBackend for IO {
+    fn draw(&mut self, screen: &vm::Screen) {
+        self.draw_screen(screen);
+    }
+
+    fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    fn set_anti_flicker(&mut self, mode: AntiFlicker) {
+        self.phosphor = Phosphor::new(mode);
+    }
+
+    fn toggle_fullscreen(&mut self) {
+        IO::toggle_fullscreen(self);
+    }
+
+    fn set_osd(&mut self, lines: &[String]) {
+        self.osd = lines.to_vec();
+    }
+
+    fn set_title(&mut self, title: &str) {
+        if let Err(error) = self.canvas.window_mut().set_title(title) {
+            eprintln!("unable to set the window title: {}", error);
+        }
+    }
+
+    fn capture(&mut self, mode: ScreenshotMode) -> Option<Image> {
+        match IO::capture(self, mode) {
+            Ok(image) => Some(image),
+            Err(error) => {
+                eprintln!("unable to capture the window: {}", error);
+                None
+            },
+        }
+    }
+}
+
+impl SdlInput {
+    fn process_input(&self) -> u16 {
+        // tracked from key events, since sdl's pressed_scancodes trips over gaps in the scancode enum
+        let keyboard = self.held
+            .iter()
+            .fold(0, |input, scancode| input | self.keys.keys_for(scancode.name()));
+        let controllers = self.controllers.as_ref().map_or(0, Controllers::keys);
+
+        keyboard | controllers
+    }
+
+    // feeds a key press to the rebinding screen: escape gives up, return keeps the current binding
+    fn rebind(&mut self, scancode: Scancode) {
+        let rebinder = match &mut self.rebinder {
+            Some(rebinder) => rebinder,
+            None => return,
+        };
+
+        let finished = match scancode {
+            Scancode::Escape => {
+                self.rebinder = None;
+                return;
+            },
+            Scancode::Return => rebinder.skip(),
+            _ => rebinder.press(scancode.name()),
+        };
 
-        // pixels
-        for y in 0..SCREEN_HEIGHT {
-            for x in 0..SCREEN_WIDTH {
-                if pixels[y][x] {
-                    c.set_draw_color(on);
-                } else {
-                    c.set_draw_color(off);
+        if let Some(keys) = finished {
+            if let Some(config) = &mut self.config {
+                if let Err(error) = config.save_key_map(&keys) {
+                    eprintln!("{}", error);
                 }
-                c.fill_rect(Rect::new((x * scale) as i32, (y * scale) as i32, scale as u32, scale as u32)).unwrap();
             }
+            self.keys = keys;
+            self.rebinder = None;
         }
+    }
+}
 
-        // grid
-        if RENDER_GRID {
-            for y in 0..SCREEN_HEIGHT {
-                for x in 0..SCREEN_WIDTH {
-                    c.set_draw_color(Color::RGB(64, 64, 64));
-                    c.draw_rect(Rect::new(
-                        (x * scale) as i32,
-                        (y * scale) as i32,
-                        scale as u32,
-                        scale as u32,
-                    )).unwrap();
-                }
+impl InputBackend for SdlInput {
+    fn poll(&mut self) -> Input {
+        let mut input = Input::default();
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in events {
+            if let Some(controllers) = &mut self.controllers {
+                controllers.handle_event(&event);
+            }
+
+            match event {
+                Event::KeyDown { scancode: Some(scancode), .. } => { self.held.insert(scancode); },
+                Event::KeyUp { scancode: Some(scancode), .. } => { self.held.remove(&scancode); },
+                _ => {},
+            }
+
+            match event {
+                Event::Quit { timestamp: _ } => input.quit = true,
+                Event::DropFile { filename, .. } => self.dropped = Some(PathBuf::from(filename)),
+                Event::KeyDown { scancode: Some(scancode), repeat: false, .. } if self.rebinder.is_some() => self.rebind(scancode),
+                Event::KeyDown { scancode: Some(scancode), repeat: false, .. } => match scancode {
+                    Scancode::Escape => input.quit = true,
+                    Scancode::F1 => self.rebinder = Some(Rebinder::new(self.keys.clone())),
+                    Scancode::F2 => input.next_theme = true,
+                    Scancode::F3 => input.toggle_mute = true,
+                    Scancode::F4 => input.cycle_osd = true,
+                    Scancode::F5 => input.reset = true,
+                    Scancode::F6 => input.toggle_pause = true,
+                    Scancode::F7 => input.advance_frame = true,
+                    Scancode::F8 => input.toggle_slow_motion = true,
+                    Scancode::F9 => input.toggle_recording = true,
+                    Scancode::F10 => input.toggle_fast_forward = true,
+                    Scancode::F11 => input.toggle_fullscreen = true,
+                    Scancode::F12 => {
+                        let shift = self.held.contains(&Scancode::LShift) || self.held.contains(&Scancode::RShift);
+                        input.screenshot = Some(if shift { ScreenshotMode::Native } else { ScreenshotMode::Display });
+                    },
+                    _ => {},
+                },
+                _ => {},
             }
         }
+        input.fast_forward = self.held.contains(&Scancode::Tab);
+
+        match &self.rebinder {
+            Some(rebinder) => Input { next_theme: false, screen: Some(rebinder.screen()), ..input },
+            None => Input { keys: self.process_input(), ..input },
+        }
+    }
+
+    fn take_dropped(&mut self) -> Option<PathBuf> {
+        self.dropped.take()
+    }
+
+    fn set_rom(&mut self, name: &str, actions: &ActionKeys) {
+        if let Some(config) = &mut self.config {
+            config.rom = name.to_string();
+            config.actions = actions.clone();
+            self.keys = config.key_map();
+            if let Some(controllers) = &mut self.controllers {
+                controllers.set_map(config.controller_map());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::rect::Rect;
+
+    use super::ScaleMode;
 
-        c.present();
+    #[test]
+    fn viewports() {
+        assert_eq!(ScaleMode::Stretch.viewport(800, 600), Rect::new(0, 0, 800, 600));
+        assert_eq!(ScaleMode::Fit.viewport(800, 600), Rect::new(0, 100, 800, 400));
+        assert_eq!(ScaleMode::Fit.viewport(1000, 200), Rect::new(300, 0, 400, 200));
+        assert_eq!(ScaleMode::Integer.viewport(800, 600), Rect::new(16, 108, 768, 384));
+        assert_eq!(ScaleMode::Integer.viewport(32, 16), Rect::new(-16, -8, 64, 32));
     }
 }
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+
+use crate::vm::{ Screen, VM, FONT, SCREEN_WIDTH };
+
+/// Host key names bound to each chip-8 key, keyed by the chip-8 key as a hex digit. This is the
+/// shape of the `keys` tables in the config file.
+pub type KeyBindings = BTreeMap<String, Vec<String>>;
+
+// the order keys are asked for when rebinding, row by row across the keypad
+const KEYPAD_ORDER: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC,
+    0x4, 0x5, 0x6, 0xD,
+    0x7, 0x8, 0x9, 0xE,
+    0xA, 0x0, 0xB, 0xF,
+];
+
+/// Maps host keys onto the hex keypad. Host keys are named the way sdl names scancodes ("W",
+/// "Up", "Keypad 8", "Space"...) and compared ignoring case; several host keys can share a
+/// chip-8 key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMap {
+    bindings: [Vec<String>; 16],
+}
+
+impl Default for KeyMap {
+    // the keypad laid over the left of a qwerty keyboard:
+    // 1 2 3 C    1 2 3 4
+    // 4 5 6 D    Q W E R
+    // 7 8 9 E    A S D F
+    // A 0 B F    Z X C V
+    fn default() -> Self {
+        const HOST_KEYS: [&str; 16] = [
+            "1", "2", "3", "4",
+            "Q", "W", "E", "R",
+            "A", "S", "D", "F",
+            "Z", "X", "C", "V",
+        ];
+
+        let mut map = Self::empty();
+        for (key, host) in KEYPAD_ORDER.iter().zip(HOST_KEYS) {
+            map.bind(*key, vec![host.to_string()]);
+        }
+        map
+    }
+}
+
+impl KeyMap {
+    /// The default for game controllers: the d-pad and left stick on 5/7/8/9, which most games
+    /// use for movement, and the face buttons on the keys either side.
+    pub fn controller() -> Self {
+        let mut map = Self::empty();
+        let bindings: [(u8, &[&str]); 8] = [
+            (0x5, &["dpup", "lefty-"]),
+            (0x8, &["dpdown", "lefty+"]),
+            (0x7, &["dpleft", "leftx-"]),
+            (0x9, &["dpright", "leftx+"]),
+            (0x6, &["a"]),
+            (0x4, &["b"]),
+            (0x0, &["back"]),
+            (0xF, &["start"]),
+        ];
+        for (key, hosts) in bindings {
+            map.bind(key, hosts.iter().map(|host| host.to_string()).collect());
+        }
+        map
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            bindings: Default::default(),
+        }
+    }
+
+    pub fn bind(&mut self, key: u8, hosts: Vec<String>) {
+        self.bindings[key as usize] = hosts;
+    }
+
+    /// Binds one more host key to `key`, keeping the ones it already has.
+    pub fn add(&mut self, key: u8, host: String) {
+        let hosts = &mut self.bindings[key as usize];
+        if !hosts.iter().any(|bound| bound.eq_ignore_ascii_case(&host)) {
+            hosts.push(host);
+        }
+    }
+
+    /// Removes a host key from every chip-8 key it's bound to.
+    pub fn unbind_host(&mut self, host: &str) {
+        for hosts in self.bindings.iter_mut() {
+            hosts.retain(|bound| !bound.eq_ignore_ascii_case(host));
+        }
+    }
+
+    /// Replaces the bindings of every chip-8 key mentioned in `bindings`, leaving the rest alone.
+    pub fn apply(&mut self, bindings: &KeyBindings) -> Result<(), String> {
+        for (key, hosts) in bindings {
+            self.bind(Self::parse_key(key)?, hosts.clone());
+        }
+        Ok(())
+    }
+
+    /// The chip-8 keys bound to a host key, as a keypad bitmask.
+    pub fn keys_for(&self, host: &str) -> u16 {
+        let mut keys = 0;
+        for (key, hosts) in self.bindings.iter().enumerate() {
+            if hosts.iter().any(|bound| bound.eq_ignore_ascii_case(host)) {
+                keys |= 1 << key;
+            }
+        }
+        keys
+    }
+
+    /// Every binding, in the shape the config file stores them.
+    pub fn bindings(&self) -> KeyBindings {
+        self.bindings
+            .iter()
+            .enumerate()
+            .map(|(key, hosts)| (format!("{:X}", key), hosts.clone()))
+            .collect()
+    }
+
+    fn parse_key(key: &str) -> Result<u8, String> {
+        match u8::from_str_radix(key, 16) {
+            Ok(key) if key < 16 => Ok(key),
+            _ => Err(format!("{:?} is not a chip-8 key, expected a hex digit 0-F", key)),
+        }
+    }
+}
+
+/// Walks through the keypad asking for a new host key for each chip-8 key in turn. The frontend
+/// feeds it key presses and shows `screen()` until it hands back the finished map.
+pub struct Rebinder {
+    map: KeyMap,
+    position: usize,
+}
+
+impl Rebinder {
+    pub fn new(map: KeyMap) -> Self {
+        Self { map, position: 0 }
+    }
+
+    /// The chip-8 key currently waiting for a host key.
+    pub fn current(&self) -> u8 {
+        KEYPAD_ORDER[self.position]
+    }
+
+    /// Binds `host` to the current key and moves on, returning the map once every key is done.
+    pub fn press(&mut self, host: &str) -> Option<KeyMap> {
+        self.map.bind(self.current(), vec![host.to_string()]);
+        self.advance()
+    }
+
+    /// Keeps the current key's bindings as they are and moves on.
+    pub fn skip(&mut self) -> Option<KeyMap> {
+        self.advance()
+    }
+
+    fn advance(&mut self) -> Option<KeyMap> {
+        self.position += 1;
+        if self.position == KEYPAD_ORDER.len() {
+            Some(self.map.clone())
+        } else {
+            None
+        }
+    }
+
+    /// The key being asked for, drawn large with the font sprites, above a row of dots marking
+    /// how far through the keypad we are.
+    pub fn screen(&self) -> Screen {
+        const SCALE: usize = 4;
+        let mut screen = VM::create_screen();
+
+        let glyph = FONT[self.current() as usize];
+        let left = (SCREEN_WIDTH - 4 * SCALE) / 2;
+        let top = 4;
+        for (row, byte) in glyph.iter().enumerate() {
+            for column in 0..4 {
+                if byte & (0x80 >> column) != 0 {
+                    for y in 0..SCALE {
+                        for x in 0..SCALE {
+                            screen[top + row * SCALE + y][left + column * SCALE + x] = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        let left = (SCREEN_WIDTH - KEYPAD_ORDER.len() * 2) / 2;
+        for done in 0..=self.position {
+            screen[28][left + done * 2] = true;
+        }
+
+        screen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ KeyMap, KeyBindings, Rebinder };
+
+    #[test]
+    fn default_layout() {
+        let map = KeyMap::default();
+        assert_eq!(map.keys_for("1"), 1 << 0x1);
+        assert_eq!(map.keys_for("4"), 1 << 0xC);
+        assert_eq!(map.keys_for("x"), 1 << 0x0);
+        assert_eq!(map.keys_for("V"), 1 << 0xF);
+        assert_eq!(map.keys_for("Up"), 0);
+    }
+
+    #[test]
+    fn apply_overrides() {
+        let mut bindings = KeyBindings::new();
+        bindings.insert("5".to_string(), vec!["W".to_string(), "Up".to_string()]);
+        bindings.insert("c".to_string(), vec!["W".to_string()]);
+
+        let mut map = KeyMap::default();
+        map.apply(&bindings).unwrap();
+        assert_eq!(map.keys_for("up"), 1 << 0x5);
+        assert_eq!(map.keys_for("W"), 1 << 0x5 | 1 << 0xC);
+        assert_eq!(map.keys_for("4"), 0);
+        assert_eq!(map.keys_for("Q"), 1 << 0x4);
+
+        bindings.insert("10".to_string(), vec![]);
+        assert!(map.apply(&bindings).is_err());
+    }
+
+    #[test]
+    fn rebind_every_key() {
+        let mut rebinder = Rebinder::new(KeyMap::default());
+        assert_eq!(rebinder.current(), 0x1);
+        assert_eq!(rebinder.press("Up"), None);
+        assert_eq!(rebinder.current(), 0x2);
+
+        for _ in 0..14 {
+            assert_eq!(rebinder.skip(), None);
+        }
+        let map = rebinder.press("Space").unwrap();
+        assert_eq!(map.keys_for("Up"), 1 << 0x1);
+        assert_eq!(map.keys_for("1"), 0);
+        assert_eq!(map.keys_for("Space"), 1 << 0xF);
+        assert_eq!(map.keys_for("W"), 1 << 0x5);
+    }
+}
@@ -0,0 +1,222 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{ Path, PathBuf };
+
+use crate::romdb::RomDatabase;
+
+const MAX_RECENT: usize = 10;
+
+// files in the rom directory worth listing
+const LISTED_EXTENSIONS: [&str; 10] = ["ch8", "c8", "sc8", "xo8", "rom", "bin", "8o", "gif", "gz", "zip"];
+
+/// A rom on the launcher's list, titled from the rom database if it's in there and otherwise by
+/// its file name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomEntry {
+    pub path: PathBuf,
+    pub title: String,
+    pub platform: Option<String>,
+    pub recent: bool,
+}
+
+impl RomEntry {
+    pub fn new(path: PathBuf, database: Option<&RomDatabase>) -> Self {
+        let info = database.and_then(|database| database.lookup(&fs::read(&path).ok()?));
+        let title = match &info {
+            Some(info) => info.title.clone(),
+            None => path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned()),
+        };
+        Self { path, title, platform: info.map(|info| info.platform), recent: false }
+    }
+}
+
+/// The roms in `dir`, by title.
+pub fn scan(dir: &Path, database: Option<&RomDatabase>) -> Result<Vec<RomEntry>, String> {
+    let read = fs::read_dir(dir).map_err(|error| format!("unable to read {}: {}", dir.display(), error))?;
+    let mut entries: Vec<RomEntry> = read
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| path.extension().is_some_and(|extension| {
+            LISTED_EXTENSIONS.contains(&extension.to_string_lossy().to_ascii_lowercase().as_str())
+        }))
+        .map(|path| RomEntry::new(path, database))
+        .collect();
+    entries.sort_by_key(|entry| entry.title.to_lowercase());
+    Ok(entries)
+}
+
+/// Where recently played roms are remembered: the user's data directory, or `None` if there's no
+/// telling where that is.
+pub fn default_recent_path() -> Option<PathBuf> {
+    recent_path(|name| env::var_os(name))
+}
+
+fn recent_path(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    // relative paths aren't usable, as they'd depend on the working directory
+    let dir = |name| var(name).map(PathBuf::from).filter(|dir| dir.is_absolute());
+    let data = dir("XDG_DATA_HOME")
+        .or_else(|| dir("APPDATA"))
+        .or_else(|| dir("HOME").map(|home| home.join(".local").join("share")))?;
+    Some(data.join("chip-8-rs").join("recent.txt"))
+}
+
+/// The roms played last, most recent first, kept in a file with a path a line.
+pub struct RecentFiles {
+    path: PathBuf,
+    files: Vec<PathBuf>,
+}
+
+impl RecentFiles {
+    /// Reads the list at `path`. A missing file is an empty list.
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let files = match fs::read_to_string(&path) {
+            Ok(text) => text.lines().filter(|line| !line.is_empty()).map(PathBuf::from).collect(),
+            Err(error) if error.kind() == ErrorKind::NotFound => vec![],
+            Err(error) => return Err(format!("unable to read {}: {}", path.display(), error)),
+        };
+        Ok(Self { path, files })
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Moves `rom` to the top of the list and saves it.
+    pub fn add(&mut self, rom: &Path) -> Result<(), String> {
+        // absolute, so the list still works from another working directory
+        let rom = fs::canonicalize(rom).unwrap_or_else(|_| rom.to_path_buf());
+        self.files.retain(|file| *file != rom);
+        self.files.insert(0, rom);
+        self.files.truncate(MAX_RECENT);
+
+        let text: String = self.files.iter().map(|file| format!("{}\n", file.display())).collect();
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|error| format!("unable to create {}: {}", dir.display(), error))?;
+        }
+        fs::write(&self.path, text).map_err(|error| format!("unable to write {}: {}", self.path.display(), error))
+    }
+}
+
+/// The launcher's list and which rom on it is picked: recent roms that still exist, then
+/// everything in the rom directory.
+pub struct Launcher {
+    entries: Vec<RomEntry>,
+    selected: usize,
+}
+
+impl Launcher {
+    pub fn new(recent: &[PathBuf], roms: Vec<RomEntry>, database: Option<&RomDatabase>) -> Self {
+        let mut entries: Vec<RomEntry> = recent
+            .iter()
+            .filter(|file| file.is_file())
+            .map(|file| RomEntry { recent: true, ..RomEntry::new(file.clone(), database) })
+            .collect();
+        entries.extend(roms);
+        Self { entries, selected: 0 }
+    }
+
+    pub fn selected(&self) -> Option<&RomEntry> {
+        self.entries.get(self.selected)
+    }
+
+    /// Moves the selection by `rows`, stopping at either end.
+    pub fn move_by(&mut self, rows: isize) {
+        let last = self.entries.len().saturating_sub(1);
+        self.selected = self.selected.saturating_add_signed(rows).min(last);
+    }
+
+    /// Selects the next rom after the current one whose title starts with `letter`, going round
+    /// to the top, like typing in a file list.
+    pub fn jump(&mut self, letter: char) {
+        let count = self.entries.len();
+        let letter = letter.to_ascii_lowercase();
+        let found = (1..=count)
+            .map(|offset| (self.selected + offset) % count)
+            .find(|index| self.entries[*index].title.to_lowercase().starts_with(letter));
+        if let Some(index) = found {
+            self.selected = index;
+        }
+    }
+
+    /// The page of the list holding the selection, `rows` long, and where the selection is on
+    /// it. Recent roms are starred.
+    pub fn page(&self, rows: usize) -> (Vec<String>, usize) {
+        let rows = rows.max(1);
+        let top = self.selected / rows * rows;
+        let lines = self.entries
+            .iter()
+            .skip(top)
+            .take(rows)
+            .map(|entry| {
+                let marker = if entry.recent { "* " } else { "" };
+                match &entry.platform {
+                    Some(platform) => format!("{}{} ({})", marker, entry.title, platform),
+                    None => format!("{}{}", marker, entry.title),
+                }
+            })
+            .collect();
+        (lines, self.selected - top)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{ recent_path, scan, Launcher, RecentFiles };
+
+    #[test]
+    fn lists_and_navigates() {
+        let dir = env::temp_dir().join(format!("chip-8-rs-launcher-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["pong.ch8", "Breakout.c8", "tetris.ch8", "notes.txt"] {
+            fs::write(dir.join(name), [0x12, 0x00]).unwrap();
+        }
+
+        let roms = scan(&dir, None).unwrap();
+        let titles: Vec<&str> = roms.iter().map(|entry| entry.title.as_str()).collect();
+        assert_eq!(titles, ["Breakout", "pong", "tetris"]);
+
+        let recent_path = dir.join("data").join("recent.txt");
+        let mut recent = RecentFiles::load(recent_path.clone()).unwrap();
+        recent.add(&dir.join("tetris.ch8")).unwrap();
+        recent.add(&dir.join("pong.ch8")).unwrap();
+        recent.add(&dir.join("tetris.ch8")).unwrap();
+        let recent = RecentFiles::load(recent_path).unwrap();
+        assert_eq!(recent.files().len(), 2);
+        assert!(recent.files()[0].ends_with("tetris.ch8"));
+
+        let mut launcher = Launcher::new(recent.files(), roms, None);
+        assert_eq!(launcher.page(10).0, ["* tetris", "* pong", "Breakout", "pong", "tetris"]);
+        launcher.move_by(3);
+        assert_eq!(launcher.page(2), (vec!["Breakout".to_string(), "pong".to_string()], 1));
+        launcher.move_by(10);
+        assert_eq!(launcher.selected().unwrap().title, "tetris");
+        launcher.jump('P');
+        assert_eq!(launcher.page(10).1, 1);
+        launcher.jump('p');
+        assert_eq!(launcher.page(10).1, 3);
+        launcher.move_by(-10);
+        assert_eq!(launcher.page(10).1, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recent_path_is_per_user() {
+        let vars = |set: &'static [(&'static str, &'static str)]| {
+            move |name: &str| set.iter().find(|(var, _)| *var == name).map(|(_, value)| OsString::from(value))
+        };
+
+        assert_eq!(recent_path(vars(&[("HOME", "/home/ann")])), Some(PathBuf::from("/home/ann/.local/share/chip-8-rs/recent.txt")));
+        assert_eq!(recent_path(vars(&[("HOME", "/home/ann"), ("XDG_DATA_HOME", "/data")])), Some(PathBuf::from("/data/chip-8-rs/recent.txt")));
+        assert_eq!(recent_path(vars(&[("HOME", "/home/ann"), ("XDG_DATA_HOME", "data")])), Some(PathBuf::from("/home/ann/.local/share/chip-8-rs/recent.txt")));
+        assert_eq!(recent_path(vars(&[("HOME", "")])), None);
+    }
+}
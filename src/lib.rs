@@ -1,3 +1,22 @@
 pub mod vm;
+pub mod audio;
+pub mod cartridge;
+pub mod config;
+pub mod controller;
+pub mod filters;
+pub mod frontend;
 pub mod io;
-pub mod system;
\ No newline at end of file
+pub mod keymap;
+pub mod launcher;
+pub mod memory;
+pub mod octo;
+pub mod osd;
+pub mod palette;
+pub mod phosphor;
+pub mod recorder;
+pub mod rom;
+pub mod romdb;
+pub mod screenshot;
+pub mod system;
+pub mod terminal;
+pub mod threaded;
\ No newline at end of file
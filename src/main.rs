@@ -1,12 +1,298 @@
 extern crate sdl2;
 
-use system::System;
+use std::env;
+use std::path::{ Path, PathBuf };
+use std::process;
+
+use audio::Waveform;
+use config::{ ConfigFile, DEFAULT_CONFIG_PATH };
+use filters::Filter;
+use frontend::{ Frontend, ScriptedInput };
+use io::{ ScaleMode, WindowOptions };
+use launcher::{ Launcher, RecentFiles };
+use memory::{ InterpreterWrites, MemoryFill, MemoryMap };
+use osd::OsdMode;
+use palette::Palette;
+use phosphor::AntiFlicker;
+use recorder::RecordFormat;
+use rom::RomLoader;
+use romdb::RomDatabase;
+use system::{ System, Backend, Runtime, Watch };
+use terminal::TerminalMode;
+use vm::{ StackConfig, StackOverflow };
 
 pub mod vm;
+pub mod audio;
+pub mod cartridge;
+pub mod config;
+pub mod controller;
+pub mod filters;
+pub mod frontend;
 pub mod io;
+pub mod keymap;
+pub mod launcher;
+pub mod memory;
+pub mod octo;
+pub mod osd;
+pub mod palette;
+pub mod phosphor;
+pub mod recorder;
+pub mod rom;
+pub mod romdb;
+pub mod screenshot;
 pub mod system;
+pub mod terminal;
+pub mod threaded;
+
+const USAGE: &str = "usage: chip-8-rs [--config=path] [--database=dir] [--roms=dir] [--screenshot-dir=dir] [--load-address=0x200] [--theme=name] [--colors=#rrggbb,...] [--anti-flicker=off|or|decay[:rate]] [--scale=fit|integer|stretch] [--fullscreen] [--filters=scanlines,grid,bloom,curvature[:strength]] [--osd=off|stats|debug] [--terminal[=half-block|braille]] [--headless[=frames]] [--record=out.gif|out.y4m] [--record-format=gif|y4m] [--audio-out=out.wav] [--waveform=square|sine|triangle] [--frequency=hz] [--volume=0-1] [--threaded] [--stack-depth=16] [--stack-overflow=error|wrap] [--vip-stack] [--font-address=0x50] [--interpreter-writes=allow|log|protect] [--memory-fill=zero|random|0xNN] [--runtime=threads|single] [--watch[=seed,replay]] [rom|cartridge.gif|source.8o|-]";
 
 pub fn main() {
     let mut sys = System::new();
-    sys.init(String::from("pong.ch8"));
-}
\ No newline at end of file
+    let mut rom_path = None;
+    let mut rom_dir = None;
+    let mut config_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+    let mut database_path = None;
+    let mut screenshot_dir = None;
+    let mut load_address = None;
+    let mut palette = None;
+    let mut anti_flicker = None;
+    let mut scale = None;
+    let mut fullscreen = None;
+    let mut filters = None;
+    let mut osd = None;
+    let mut watch = None;
+    let mut stack_depth = None;
+    let mut stack_overflow = None;
+    let mut vip_stack = false;
+    let mut memory_map = MemoryMap::default();
+    let mut terminal = None;
+    let mut headless = None;
+    let mut waveform = None;
+    let mut frequency = None;
+    let mut volume = None;
+
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--terminal" | "--terminal=half-block" => terminal = Some(TerminalMode::HalfBlock),
+            "--terminal=braille" => terminal = Some(TerminalMode::Braille),
+            "--threaded" => sys.set_backend(Backend::Threaded),
+            _ if arg.starts_with("--runtime=") => sys.set_runtime(Runtime::parse(&arg["--runtime=".len()..]).unwrap_or_else(|error| fail(error))),
+            _ if arg.starts_with("--stack-depth=") => match arg["--stack-depth=".len()..].parse::<usize>() {
+                Ok(depth) => stack_depth = Some(depth),
+                Err(_) => fail(format!("invalid stack depth {}", &arg["--stack-depth=".len()..])),
+            },
+            _ if arg.starts_with("--stack-overflow=") => stack_overflow = Some(StackOverflow::parse(&arg["--stack-overflow=".len()..]).unwrap_or_else(|error| fail(error))),
+            "--vip-stack" => vip_stack = true,
+            _ if arg.starts_with("--font-address=") => {
+                let address = &arg["--font-address=".len()..];
+                match u16::from_str_radix(address.trim_start_matches("0x"), 16) {
+                    Ok(address) => memory_map.font_address = address,
+                    Err(_) => fail(format!("invalid font address {}, expected hex like 0x50", address)),
+                }
+            },
+            _ if arg.starts_with("--interpreter-writes=") => {
+                memory_map.interpreter_writes = InterpreterWrites::parse(&arg["--interpreter-writes=".len()..]).unwrap_or_else(|error| fail(error));
+            },
+            _ if arg.starts_with("--memory-fill=") => memory_map.fill = MemoryFill::parse(&arg["--memory-fill=".len()..]).unwrap_or_else(|error| fail(error)),
+            "--headless" => headless = Some(None),
+            "--watch" => watch = Some(Watch::default()),
+            _ if arg.starts_with("--watch=") => watch = Some(Watch::parse(&arg["--watch=".len()..]).unwrap_or_else(|error| fail(error))),
+            _ if arg.starts_with("--headless=") => match arg["--headless=".len()..].parse::<usize>() {
+                Ok(frames) => headless = Some(Some(frames)),
+                Err(_) => fail(format!("invalid frame count {}", &arg["--headless=".len()..])),
+            },
+            _ if arg.starts_with("--record=") => {
+                let path = PathBuf::from(&arg["--record=".len()..]);
+                RecordFormat::from_path(&path).unwrap_or_else(|error| fail(error));
+                sys.set_recording(path);
+            },
+            _ if arg.starts_with("--waveform=") => waveform = Some(Waveform::parse(&arg["--waveform=".len()..]).unwrap_or_else(|error| fail(error))),
+            _ if arg.starts_with("--frequency=") => match arg["--frequency=".len()..].parse::<f32>() {
+                Ok(hz) => frequency = Some(hz),
+                Err(_) => fail(format!("invalid frequency {}", &arg["--frequency=".len()..])),
+            },
+            _ if arg.starts_with("--volume=") => match arg["--volume=".len()..].parse::<f32>() {
+                Ok(level) => volume = Some(level),
+                Err(_) => fail(format!("invalid volume {}", &arg["--volume=".len()..])),
+            },
+            _ if arg.starts_with("--audio-out=") => sys.set_audio_output(PathBuf::from(&arg["--audio-out=".len()..])),
+            _ if arg.starts_with("--record-format=") => {
+                sys.set_record_format(RecordFormat::parse(&arg["--record-format=".len()..]).unwrap_or_else(|error| fail(error)));
+            },
+            "--fullscreen" => fullscreen = Some(true),
+            _ if arg.starts_with("--scale=") => scale = Some(ScaleMode::parse(&arg["--scale=".len()..]).unwrap_or_else(|error| fail(error))),
+            _ if arg.starts_with("--filters=") => {
+                let parsed: Result<Vec<Filter>, String> = arg["--filters=".len()..].split(',').filter(|filter| !filter.is_empty()).map(Filter::parse).collect();
+                filters = Some(parsed.unwrap_or_else(|error| fail(error)));
+            },
+            _ if arg.starts_with("--osd=") => osd = Some(OsdMode::parse(&arg["--osd=".len()..]).unwrap_or_else(|error| fail(error))),
+            _ if arg.starts_with("--config=") => config_path = PathBuf::from(&arg["--config=".len()..]),
+            _ if arg.starts_with("--roms=") => rom_dir = Some(PathBuf::from(&arg["--roms=".len()..])),
+            _ if arg.starts_with("--database=") => database_path = Some(PathBuf::from(&arg["--database=".len()..])),
+            _ if arg.starts_with("--screenshot-dir=") => screenshot_dir = Some(PathBuf::from(&arg["--screenshot-dir=".len()..])),
+            _ if arg.starts_with("--theme=") => palette = Some(Palette::theme(&arg["--theme=".len()..]).unwrap_or_else(|error| fail(error))),
+            _ if arg.starts_with("--colors=") => {
+                let colors: Vec<&str> = arg["--colors=".len()..].split(',').collect();
+                palette = Some(Palette::parse(&colors).unwrap_or_else(|error| fail(error)));
+            },
+            _ if arg.starts_with("--anti-flicker=") => {
+                anti_flicker = Some(AntiFlicker::parse(&arg["--anti-flicker=".len()..]).unwrap_or_else(|error| fail(error)));
+            },
+            _ if arg.starts_with("--load-address=") => {
+                let address = &arg["--load-address=".len()..];
+                match u16::from_str_radix(address.trim_start_matches("0x"), 16) {
+                    Ok(address) => load_address = Some(address),
+                    Err(_) => fail(format!("invalid load address {}, expected hex like 0x600", address)),
+                }
+            },
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if arg.starts_with('-') && arg != "-" => {
+                eprintln!("unknown option {}\n{}", arg, USAGE);
+                process::exit(2);
+            },
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    let mut config = ConfigFile::load(config_path.clone(), String::new()).unwrap_or_else(|error| fail(error));
+    let database = database_path.or(config.config.database.clone())
+        .map(|path| RomDatabase::load(&path).unwrap_or_else(|error| fail(error)));
+
+    // the launcher is only for the window, and recent roms only for when it's used. without it
+    // there's still pong to fall back on
+    let windowed = headless.is_none() && terminal.is_none();
+    let mut recent = (windowed && rom_path.is_none())
+        .then(launcher::default_recent_path)
+        .flatten()
+        .map(|path| RecentFiles::load(path).unwrap_or_else(|error| fail(error)));
+    let rom_path = match rom_path {
+        Some(path) => path,
+        None if windowed => {
+            let dir = rom_dir.or(config.config.rom_dir.clone()).unwrap_or_else(|| PathBuf::from("."));
+            let roms = launcher::scan(&dir, database.as_ref()).unwrap_or_else(|error| fail(error));
+            let launcher = Launcher::new(recent.as_ref().map_or(&[], RecentFiles::files), roms, database.as_ref());
+            match io::launch(launcher, fullscreen.or(config.config.fullscreen).unwrap_or(false)) {
+                Some(path) => path.to_string_lossy().into_owned(),
+                None => return,
+            }
+        },
+        None => String::from("pong.ch8"),
+    };
+    if let Some(recent) = &mut recent {
+        recent.add(Path::new(&rom_path)).unwrap_or_else(|error| eprintln!("{}", error));
+    }
+
+    // per-rom settings are keyed by the rom's file name
+    config.rom = Path::new(&rom_path).file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+
+    let mut loader = RomLoader::new();
+    if let Some(address) = load_address {
+        loader.set_load_address(address).unwrap_or_else(|error| fail(error));
+    }
+    let mut rom = loader.load_path(Path::new(&rom_path)).unwrap_or_else(|error| fail(error));
+
+    // known roms get their platform's quirks, speed, keys and colours from the database
+    if let Some(info) = database.as_ref().and_then(|database| database.lookup(&rom.bytes)) {
+        if info.supported {
+            println!("Running {} as {}", info.title, info.platform);
+        } else {
+            eprintln!("warning: {} is a {} rom, which isn't supported, so it may not run properly", info.title, info.platform);
+        }
+        sys.set_quirks(info.quirks);
+        if let Some(ticks_per_second) = info.ticks_per_second() {
+            sys.set_ticks_per_second(ticks_per_second).unwrap_or_else(|error| fail(error));
+        }
+        if let Some(palette) = info.palette {
+            sys.set_palette(palette);
+        }
+        if let (Some(address), None) = (info.start_address, load_address) {
+            loader.set_load_address(address).unwrap_or_else(|error| fail(error));
+        }
+        config.actions = info.keys;
+    }
+    if let Some(database) = database {
+        sys.set_rom_database(database, load_address);
+    }
+    if let Some(recent) = recent {
+        sys.set_recent_files(recent);
+    }
+    if let Some(watch) = watch {
+        if rom_path == "-" {
+            fail("unable to watch stdin for changes".to_string());
+        }
+        sys.set_watch(PathBuf::from(&rom_path), watch);
+    }
+    sys.set_rom_loader(loader);
+
+    // the vip's twelve levels, unless asked for more or fewer
+    let mut stack = if vip_stack { StackConfig::vip() } else { StackConfig::default() };
+    stack.depth = stack_depth.unwrap_or(stack.depth);
+    stack.overflow = stack_overflow.unwrap_or(stack.overflow);
+    stack.validate().unwrap_or_else(|error| fail(error));
+    sys.set_stack(stack);
+    sys.set_memory_map(memory_map).unwrap_or_else(|error| fail(error));
+
+    if let Some(dir) = screenshot_dir.or(config.config.screenshot_dir.clone()) {
+        sys.set_screenshot_dir(dir);
+    }
+
+    let mut tone = config.tone();
+    tone.waveform = waveform.unwrap_or(tone.waveform);
+    tone.frequency = frequency.unwrap_or(tone.frequency);
+    tone.volume = volume.unwrap_or(tone.volume);
+    tone.validate().unwrap_or_else(|error| fail(error));
+    sys.set_tone(tone);
+
+    if let Some(mode) = osd.or(config.osd_mode()) {
+        sys.set_osd_mode(mode);
+    }
+
+    if let Some(anti_flicker) = anti_flicker.or(config.anti_flicker()) {
+        sys.set_anti_flicker(anti_flicker);
+    }
+
+    // colours picked by the user win over the database's and the cartridge's
+    if let Some(palette) = palette.or(config.palette()) {
+        sys.set_palette(palette);
+        if let Some(options) = &mut rom.options {
+            options.palette = None;
+        }
+    }
+
+    match (headless, terminal) {
+        // no window, sound or input, for running and recording roms from scripts and ci
+        (Some(frames), _) => {
+            sys.set_quit_on_error(true);
+            sys.set_frontend(move || match frames {
+                Some(frames) => Frontend { input: Box::new(ScriptedInput::new(vec![0; frames])), ..Frontend::null() },
+                None => Frontend::null(),
+            });
+        },
+        (None, Some(mode)) => {
+            sys.set_quiet(true);
+            let keys = config.key_map();
+            sys.set_frontend(move || terminal::frontend(mode, keys).expect("unable to set up the terminal"));
+        },
+        (None, None) => {
+            let window = WindowOptions {
+                scale: scale.or(config.scale_mode()).unwrap_or_default(),
+                fullscreen: fullscreen.or(config.config.fullscreen).unwrap_or(false),
+                filters: filters.unwrap_or_else(|| config.filters()),
+            };
+            sys.set_frontend(move || io::frontend(Some(config), window));
+        },
+    }
+
+    if let Err(error) = sys.run(rom) {
+        eprintln!("{}", error);
+        process::exit(error.exit_code());
+    }
+}
+
+fn fail(error: String) -> ! {
+    eprintln!("{}", error);
+    process::exit(2);
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::vm::{ FONT, MEMORY_BYTES, VIP_STACK };
+
+/// Where the original interpreter lived. Roms are loaded after it.
+pub const INTERPRETER_AREA: Range<u16> = 0x000..0x200;
+
+/// What a part of memory is for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    Interpreter,
+    Font,
+    Program, // the rom as loaded, code and data alike
+    Stack, // only when the stack is kept in memory
+    Free,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Region::Interpreter => "interpreter",
+            Region::Font => "font",
+            Region::Program => "program",
+            Region::Stack => "stack",
+            Region::Free => "free",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What happens when a rom writes into the interpreter area or the font.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InterpreterWrites {
+    #[default]
+    Allow,
+    Log, // allowed, but printed to stderr
+    Protect, // stops the vm with VmError::ProtectedWrite
+}
+
+impl InterpreterWrites {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "allow" => Ok(Self::Allow),
+            "log" => Ok(Self::Log),
+            "protect" => Ok(Self::Protect),
+            _ => Err(format!("unknown interpreter write handling {}, expected allow, log or protect", name)),
+        }
+    }
+}
+
+/// What memory holds before the font and rom are written into it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MemoryFill {
+    #[default]
+    Zero,
+    Byte(u8),
+    Random, // from the vm's generator, so seeded runs still repeat
+}
+
+impl MemoryFill {
+    /// `zero`, `random` or a hex byte like `0xFF`.
+    pub fn parse(fill: &str) -> Result<Self, String> {
+        match fill {
+            "zero" => Ok(Self::Zero),
+            "random" => Ok(Self::Random),
+            _ => u8::from_str_radix(fill.trim_start_matches("0x"), 16)
+                .map(Self::Byte)
+                .map_err(|_| format!("invalid memory fill {}, expected zero, random or a hex byte like 0xFF", fill)),
+        }
+    }
+}
+
+/// How the vm lays out its 4k, and where the loaded rom and stack ended up in it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryMap {
+    pub font_address: u16, // 0 by default, 0x50 in most other interpreters
+    pub interpreter_writes: InterpreterWrites,
+    pub fill: MemoryFill,
+    pub(crate) program: Range<u16>,
+    pub(crate) stack_in_memory: bool,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self {
+            font_address: 0,
+            interpreter_writes: InterpreterWrites::Allow,
+            fill: MemoryFill::Zero,
+            program: 0x200..0x200,
+            stack_in_memory: false,
+        }
+    }
+}
+
+impl MemoryMap {
+    /// Checks the font fits in the interpreter area, where roms loaded at 0x200 or above can't
+    /// overwrite it.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.font().end > INTERPRETER_AREA.end {
+            return Err(format!("the font has to fit below {:#05X}, not start at {:#05X}", INTERPRETER_AREA.end, self.font_address));
+        }
+        Ok(())
+    }
+
+    pub fn font(&self) -> Range<u16> {
+        self.font_address..self.font_address.saturating_add((FONT.len() * FONT[0].len()) as u16)
+    }
+
+    pub fn program(&self) -> Range<u16> {
+        self.program.clone()
+    }
+
+    /// What `address` is for. The font and stack win over anything they sit in.
+    pub fn region(&self, address: u16) -> Region {
+        if self.font().contains(&address) {
+            Region::Font
+        } else if self.stack_in_memory && VIP_STACK.contains(&address) {
+            Region::Stack
+        } else if self.program.contains(&address) {
+            Region::Program
+        } else if INTERPRETER_AREA.contains(&address) {
+            Region::Interpreter
+        } else {
+            Region::Free
+        }
+    }
+
+    /// Whether a write to `address` is one `interpreter_writes` cares about.
+    pub fn interpreter_owned(&self, address: u16) -> bool {
+        matches!(self.region(address), Region::Interpreter | Region::Font)
+    }
+
+    /// All of memory as consecutive runs of the same region, for listing.
+    pub fn spans(&self) -> Vec<(Region, Range<u16>)> {
+        let mut spans: Vec<(Region, Range<u16>)> = vec![];
+        for address in 0..MEMORY_BYTES as u16 {
+            let region = self.region(address);
+            match spans.last_mut() {
+                Some((last, range)) if *last == region => range.end = address + 1,
+                _ => spans.push((region, address..address + 1)),
+            }
+        }
+        spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ InterpreterWrites, MemoryFill, MemoryMap, Region };
+
+    #[test]
+    fn regions() {
+        let map = MemoryMap { font_address: 0x50, program: 0x200..0x300, stack_in_memory: true, ..MemoryMap::default() };
+        assert_eq!(map.region(0x000), Region::Interpreter);
+        assert_eq!(map.region(0x050), Region::Font);
+        assert_eq!(map.region(0x09F), Region::Font);
+        assert_eq!(map.region(0x2FF), Region::Program);
+        assert_eq!(map.region(0xECF), Region::Stack);
+        assert_eq!(map.region(0xFFF), Region::Free);
+        assert!(map.interpreter_owned(0x0A0));
+        assert!(!map.interpreter_owned(0x200));
+
+        let spans: Vec<String> = map.spans().iter().map(|(region, range)| format!("{} {:X}-{:X}", region, range.start, range.end)).collect();
+        assert_eq!(spans, ["interpreter 0-50", "font 50-A0", "interpreter A0-200", "program 200-300", "free 300-EA0", "stack EA0-ED0", "free ED0-1000"]);
+    }
+
+    #[test]
+    fn font_stays_below_roms() {
+        assert!(MemoryMap { font_address: 0x1B0, ..MemoryMap::default() }.validate().is_ok());
+        assert!(MemoryMap { font_address: 0x1B1, ..MemoryMap::default() }.validate().is_err());
+        assert!(MemoryMap { font_address: 0x200, ..MemoryMap::default() }.validate().is_err());
+        assert!(MemoryMap { font_address: 0xFC0, ..MemoryMap::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn options() {
+        assert_eq!(MemoryFill::parse("0xFF"), Ok(MemoryFill::Byte(0xFF)));
+        assert!(MemoryFill::parse("ones").is_err());
+        assert_eq!(InterpreterWrites::parse("protect"), Ok(InterpreterWrites::Protect));
+    }
+}
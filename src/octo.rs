@@ -0,0 +1,632 @@
+use std::collections::HashMap;
+
+// octo programs are always assembled to run from here
+const ORIGIN: usize = 0x200;
+
+/// Assembles Octo source (https://johnearnest.github.io/Octo/) into a rom to load at 0x200.
+///
+/// Covers the chip-8 subset of the language: labels, `:const`, `:alias`, `:org`, `:byte`, `:next`,
+/// `:unpack`, every chip-8 statement, `if ... then`, `if ... begin ... else ... end`,
+/// `loop ... while ... again` and the `<`, `>`, `<=`, `>=` comparisons. Macros, `:calc` and the
+/// super-chip and xo-chip extensions are rejected.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler::new(tokenize(source));
+
+    // a jump to main always comes first
+    assembler.emit_address(0x1000, "main".to_string())?;
+    while assembler.position < assembler.tokens.len() {
+        assembler.statement()?;
+    }
+    assembler.finish()
+}
+
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    for (index, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        for text in code.split_whitespace() {
+            tokens.push(Token { text, line: index + 1 });
+        }
+    }
+    tokens
+}
+
+enum Patch {
+    Address, // the low 12 bits of an instruction
+    UnpackHigh, // the low nibble of the second byte
+    UnpackLow, // the whole second byte
+}
+
+struct Fixup {
+    at: usize, // offset of the instruction in the rom
+    label: String,
+    line: usize,
+    patch: Patch,
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+// a condition in the form chip-8 can test directly, i.e. by skipping the next instruction
+#[derive(Clone, Copy)]
+enum Condition {
+    Equal(u8, Operand),
+    NotEqual(u8, Operand),
+    Key(u8),
+    NotKey(u8),
+}
+
+impl Condition {
+    fn negated(self) -> Self {
+        match self {
+            Condition::Equal(x, operand) => Condition::NotEqual(x, operand),
+            Condition::NotEqual(x, operand) => Condition::Equal(x, operand),
+            Condition::Key(x) => Condition::NotKey(x),
+            Condition::NotKey(x) => Condition::Key(x),
+        }
+    }
+}
+
+enum Block {
+    If(usize), // offset of the jump past the body
+    Else(usize), // offset of the jump past the else body
+    Loop(usize, Vec<usize>), // address of the loop start and offsets of jumps out from `while`s
+}
+
+struct Assembler<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize,
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, u16>,
+    aliases: HashMap<String, u8>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+}
+
+impl<'a> Assembler<'a> {
+    fn new(tokens: Vec<Token<'a>>) -> Self {
+        Self {
+            tokens,
+            position: 0,
+            rom: vec![],
+            here: ORIGIN,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            fixups: vec![],
+            blocks: vec![],
+        }
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, String> {
+        if let Some(block) = self.blocks.last() {
+            let missing = match block {
+                Block::Loop(..) => "again",
+                _ => "end",
+            };
+            return Err(format!("missing {} at the end of the program", missing));
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let address = *self.labels
+                .get(&fixup.label)
+                .ok_or_else(|| format!("line {}: undefined name {}", fixup.line, fixup.label))?;
+            match fixup.patch {
+                Patch::Address => {
+                    self.rom[fixup.at] |= (address >> 8) as u8 & 0x0F;
+                    self.rom[fixup.at + 1] = address as u8;
+                },
+                Patch::UnpackHigh => self.rom[fixup.at + 1] |= (address >> 8) as u8 & 0x0F,
+                Patch::UnpackLow => self.rom[fixup.at + 1] = address as u8,
+            }
+        }
+        Ok(self.rom)
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token.text)
+            },
+            None => Err("unexpected end of program".to_string()),
+        }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(|token| token.text)
+    }
+
+    fn line(&self) -> usize {
+        let token = self.tokens.get(self.position.saturating_sub(1));
+        token.map_or(0, |token| token.line)
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line(), message))
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token != expected {
+            return self.error(format!("expected {}, found {}", expected, token));
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), String> {
+        let offset = self.here - ORIGIN;
+        if self.here >= crate::vm::MEMORY_BYTES {
+            return self.error("program doesn't fit in memory".to_string());
+        }
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit_op(&mut self, opcode: u16) -> Result<(), String> {
+        self.emit((opcode >> 8) as u8)?;
+        self.emit(opcode as u8)
+    }
+
+    // an instruction taking a 12 bit address, which can be a label defined further down
+    fn emit_address(&mut self, opcode: u16, name: String) -> Result<(), String> {
+        match self.number(&name).or(self.constants.get(&name).copied()) {
+            Some(address) => self.emit_op(opcode | address & 0x0FFF),
+            None => {
+                self.fixups.push(Fixup { at: self.here - ORIGIN, label: name, line: self.line(), patch: Patch::Address });
+                self.emit_op(opcode)
+            },
+        }
+    }
+
+    // overwrites the placeholder jump at `at` with a jump to here
+    fn patch_jump(&mut self, at: usize) {
+        let opcode = 0x1000 | self.here as u16;
+        self.rom[at] = (opcode >> 8) as u8;
+        self.rom[at + 1] = opcode as u8;
+    }
+
+    fn define(&mut self, name: &str, value: u16) -> Result<(), String> {
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return self.error(format!("{} is already defined", name));
+        }
+        self.labels.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let token = self.next()?;
+        match token {
+            ":" => {
+                let name = self.next()?;
+                self.define(name, self.here as u16)?;
+            },
+            ":const" => {
+                let name = self.next()?.to_string();
+                let value = self.value()?;
+                if self.labels.contains_key(&name) {
+                    return self.error(format!("{} is already defined", name));
+                }
+                self.constants.insert(name, value);
+            },
+            ":alias" => {
+                let name = self.next()?.to_string();
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            },
+            ":org" => {
+                let address = self.value()? as usize;
+                if address < ORIGIN {
+                    return self.error(format!(":org {:#X} is below the start of the program", address));
+                }
+                self.here = address;
+            },
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(byte)?;
+            },
+            ":next" => {
+                // names the second byte of the next instruction, for self modifying code
+                let name = self.next()?;
+                self.define(name, self.here as u16 + 1)?;
+            },
+            ":unpack" => {
+                let nibble = self.value()?;
+                if nibble > 0xF {
+                    return self.error(format!("{} doesn't fit in a nibble", nibble));
+                }
+                let name = self.next()?.to_string();
+                let address = self.number(&name).or(self.constants.get(&name).copied());
+                let at = self.here - ORIGIN;
+                match address {
+                    Some(address) => {
+                        self.emit_op(0x6000 | nibble << 4 | address >> 8 & 0x0F)?;
+                        self.emit_op(0x6100 | address & 0xFF)?;
+                    },
+                    None => {
+                        let line = self.line();
+                        self.fixups.push(Fixup { at, label: name.clone(), line, patch: Patch::UnpackHigh });
+                        self.fixups.push(Fixup { at: at + 2, label: name, line, patch: Patch::UnpackLow });
+                        self.emit_op(0x6000 | nibble << 4)?;
+                        self.emit_op(0x6100)?;
+                    },
+                }
+            },
+            ":call" => {
+                let name = self.next()?.to_string();
+                self.emit_address(0x2000, name)?;
+            },
+            ":breakpoint" => {
+                self.next()?;
+            },
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            },
+            "return" | ";" => self.emit_op(0x00EE)?,
+            "clear" => self.emit_op(0x00E0)?,
+            "bcd" => self.register_op(0xF033)?,
+            "save" => self.register_op(0xF055)?,
+            "load" => self.register_op(0xF065)?,
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let height = self.value()?;
+                if height > 0xF {
+                    return self.error(format!("sprite height {} is more than 15", height));
+                }
+                self.emit_op(0xD000 | x << 8 | y << 4 | height)?;
+            },
+            "jump" => {
+                let name = self.next()?.to_string();
+                self.emit_address(0x1000, name)?;
+            },
+            "jump0" => {
+                let name = self.next()?.to_string();
+                self.emit_address(0xB000, name)?;
+            },
+            "native" => {
+                let name = self.next()?.to_string();
+                self.emit_address(0x0000, name)?;
+            },
+            "delay" => {
+                self.expect(":=")?;
+                self.register_op(0xF015)?;
+            },
+            "buzzer" => {
+                self.expect(":=")?;
+                self.register_op(0xF018)?;
+            },
+            "i" => self.index()?,
+            "if" => self.conditional()?,
+            "else" => match self.blocks.pop() {
+                Some(Block::If(at)) => {
+                    let jump = self.here - ORIGIN;
+                    self.emit_op(0x1000)?;
+                    self.patch_jump(at);
+                    self.blocks.push(Block::Else(jump));
+                },
+                _ => return self.error("else without if ... begin".to_string()),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If(at)) | Some(Block::Else(at)) => self.patch_jump(at),
+                _ => return self.error("end without if ... begin".to_string()),
+            },
+            "loop" => self.blocks.push(Block::Loop(self.here, vec![])),
+            "while" => {
+                let condition = self.condition()?;
+                self.skip_when(condition)?;
+                let jump = self.here - ORIGIN;
+                self.emit_op(0x1000)?;
+                match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop(..))) {
+                    Some(Block::Loop(_, breaks)) => breaks.push(jump),
+                    _ => return self.error("while outside of a loop".to_string()),
+                }
+            },
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop(start, breaks)) => {
+                    self.emit_op(0x1000 | start as u16)?;
+                    for at in breaks {
+                        self.patch_jump(at);
+                    }
+                },
+                _ => return self.error("again without loop".to_string()),
+            },
+            _ if self.is_register(token) => {
+                self.position -= 1;
+                self.register_statement()?;
+            },
+            _ if token.starts_with(':') => return self.error(format!("{} isn't supported", token)),
+            _ => match self.number(token).or(self.constants.get(token).copied()) {
+                Some(_) => {
+                    self.position -= 1;
+                    let byte = self.byte()?;
+                    self.emit(byte)?;
+                },
+                // anything else names a subroutine to call
+                None => self.emit_address(0x2000, token.to_string())?,
+            },
+        }
+        Ok(())
+    }
+
+    fn register_op(&mut self, opcode: u16) -> Result<(), String> {
+        let x = self.register()? as u16;
+        self.emit_op(opcode | x << 8)
+    }
+
+    fn index(&mut self) -> Result<(), String> {
+        match self.next()? {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    self.register_op(0xF029)
+                },
+                Some("bighex") | Some("long") => self.error("super-chip and xo-chip instructions aren't supported".to_string()),
+                _ => {
+                    let name = self.next()?.to_string();
+                    self.emit_address(0xA000, name)
+                },
+            },
+            "+=" => self.register_op(0xF01E),
+            operator => self.error(format!("unknown operator i {}", operator)),
+        }
+    }
+
+    fn register_statement(&mut self) -> Result<(), String> {
+        let x = self.register()? as u16;
+        let operator = self.next()?;
+        match (operator, self.peek()) {
+            (":=", Some("random")) => {
+                self.next()?;
+                let mask = self.byte()? as u16;
+                return self.emit_op(0xC000 | x << 8 | mask);
+            },
+            (":=", Some("key")) => {
+                self.next()?;
+                return self.emit_op(0xF00A | x << 8);
+            },
+            (":=", Some("delay")) => {
+                self.next()?;
+                return self.emit_op(0xF007 | x << 8);
+            },
+            _ => {},
+        }
+
+        let operand = self.operand()?;
+        let opcode = match (operator, operand) {
+            (":=", Operand::Byte(n)) => 0x6000 | n as u16,
+            ("+=", Operand::Byte(n)) => 0x7000 | n as u16,
+            ("-=", Operand::Byte(n)) => 0x7000 | n.wrapping_neg() as u16,
+            (":=", Operand::Register(y)) => 0x8000 | (y as u16) << 4,
+            ("|=", Operand::Register(y)) => 0x8001 | (y as u16) << 4,
+            ("&=", Operand::Register(y)) => 0x8002 | (y as u16) << 4,
+            ("^=", Operand::Register(y)) => 0x8003 | (y as u16) << 4,
+            ("+=", Operand::Register(y)) => 0x8004 | (y as u16) << 4,
+            ("-=", Operand::Register(y)) => 0x8005 | (y as u16) << 4,
+            (">>=", Operand::Register(y)) => 0x8006 | (y as u16) << 4,
+            ("=-", Operand::Register(y)) => 0x8007 | (y as u16) << 4,
+            ("<<=", Operand::Register(y)) => 0x800E | (y as u16) << 4,
+            _ => return self.error(format!("unknown operator {}", operator)),
+        };
+        self.emit_op(opcode | x << 8)
+    }
+
+    fn conditional(&mut self) -> Result<(), String> {
+        let condition = self.condition()?;
+        match self.next()? {
+            "then" => {
+                self.skip_when(condition.negated())?;
+                self.statement()
+            },
+            "begin" => {
+                self.skip_when(condition)?;
+                self.blocks.push(Block::If(self.here - ORIGIN));
+                self.emit_op(0x1000)
+            },
+            token => self.error(format!("expected then or begin, found {}", token)),
+        }
+    }
+
+    // reads a condition, emitting whatever it needs computed into vf first
+    fn condition(&mut self) -> Result<Condition, String> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        match operator {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            _ => {},
+        }
+
+        let operand = self.operand()?;
+        match operator {
+            "==" => return Ok(Condition::Equal(x, operand)),
+            "!=" => return Ok(Condition::NotEqual(x, operand)),
+            "<" | ">" | "<=" | ">=" => {},
+            _ => return self.error(format!("unknown comparison {}", operator)),
+        }
+
+        // subtract one side from the other in vf, leaving the no-borrow flag there
+        let x = x as u16;
+        match (operator, operand) {
+            ("<" | ">=", Operand::Register(y)) => {
+                self.emit_op(0x8F00 | x << 4)?;
+                self.emit_op(0x8F05 | (y as u16) << 4)?; // vf = x >= y
+            },
+            ("<" | ">=", Operand::Byte(n)) => {
+                self.emit_op(0x6F00 | n as u16)?;
+                self.emit_op(0x8F07 | x << 4)?; // vf = x >= n
+            },
+            (_, Operand::Register(y)) => {
+                self.emit_op(0x8F00 | (y as u16) << 4)?;
+                self.emit_op(0x8F05 | x << 4)?; // vf = y >= x
+            },
+            (_, Operand::Byte(n)) => {
+                self.emit_op(0x6F00 | n as u16)?;
+                self.emit_op(0x8F05 | x << 4)?; // vf = n >= x
+            },
+        }
+        Ok(match operator {
+            "<" | ">" => Condition::Equal(0xF, Operand::Byte(0)),
+            _ => Condition::NotEqual(0xF, Operand::Byte(0)),
+        })
+    }
+
+    // skips the next instruction when the condition holds
+    fn skip_when(&mut self, condition: Condition) -> Result<(), String> {
+        let opcode = match condition {
+            Condition::Equal(x, Operand::Byte(n)) => 0x3000 | (x as u16) << 8 | n as u16,
+            Condition::NotEqual(x, Operand::Byte(n)) => 0x4000 | (x as u16) << 8 | n as u16,
+            Condition::Equal(x, Operand::Register(y)) => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::NotEqual(x, Operand::Register(y)) => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::Key(x) => 0xE09E | (x as u16) << 8,
+            Condition::NotKey(x) => 0xE0A1 | (x as u16) << 8,
+        };
+        self.emit_op(opcode)
+    }
+
+    fn is_register(&self, token: &str) -> bool {
+        Self::parse_register(token).is_some() || self.aliases.contains_key(token)
+    }
+
+    fn parse_register(token: &str) -> Option<u8> {
+        let digit = token.strip_prefix('v').or(token.strip_prefix('V'))?;
+        match u8::from_str_radix(digit, 16) {
+            Ok(register) if digit.len() == 1 => Some(register),
+            _ => None,
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        match Self::parse_register(token).or(self.aliases.get(token).copied()) {
+            Some(register) => Ok(register),
+            None => self.error(format!("expected a register, found {}", token)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.peek() {
+            Some(token) if self.is_register(token) => Ok(Operand::Register(self.register()?)),
+            _ => Ok(Operand::Byte(self.byte()?)),
+        }
+    }
+
+    fn number(&self, token: &str) -> Option<u16> {
+        let (negative, digits) = match token.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, token),
+        };
+        let value = if let Some(hex) = digits.strip_prefix("0x") {
+            u16::from_str_radix(hex, 16)
+        } else if let Some(binary) = digits.strip_prefix("0b") {
+            u16::from_str_radix(binary, 2)
+        } else {
+            digits.parse()
+        }.ok()?;
+        Some(if negative { value.wrapping_neg() } else { value })
+    }
+
+    fn value(&mut self) -> Result<u16, String> {
+        let token = self.next()?;
+        match self.number(token).or(self.constants.get(token).copied()).or(self.labels.get(token).copied()) {
+            Some(value) => Ok(value),
+            None => self.error(format!("expected a number, found {}", token)),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let value = self.value()?;
+        // negative numbers down to -128 are fine as bytes
+        if value > 0xFF && value < 0xFF80 {
+            return self.error(format!("{} doesn't fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assemble;
+
+    #[test]
+    fn assembles_program() {
+        let rom = assemble("
+            :const SPEED 2
+            :alias x v1
+
+            : main
+                clear
+                x := 0
+                loop
+                    x += SPEED
+                    if x == 10 then draw
+                    while x != 20
+                again
+                if v0 key begin
+                    v2 := random 0xFF
+                else
+                    i := sprite
+                end
+                if x < v2 then return
+                jump main
+
+            : draw  # subroutine defined after it's called
+                i := hex x
+                sprite x x 5
+                ;
+
+            : sprite
+                0b11110000 -1
+        ").unwrap();
+
+        assert_eq!(rom, [
+            0x12, 0x02, // jump main
+            0x00, 0xE0, // 0x202: clear
+            0x61, 0x00, // x := 0
+            0x71, 0x02, // 0x206: x += SPEED
+            0x41, 0x0A, // if x == 10 then
+            0x22, 0x26, // draw
+            0x41, 0x14, // while x != 20
+            0x12, 0x12,
+            0x12, 0x06, // again
+            0xE0, 0x9E, // 0x212: if v0 key begin
+            0x12, 0x1A,
+            0xC2, 0xFF, // v2 := random 0xFF
+            0x12, 0x1C, // else
+            0xA2, 0x2C, // 0x21A: i := sprite
+            0x8F, 0x10, // 0x21C: if x < v2 then
+            0x8F, 0x25,
+            0x4F, 0x00,
+            0x00, 0xEE, // return
+            0x12, 0x02, // jump main
+            0xF1, 0x29, // 0x226: draw
+            0xD1, 0x15,
+            0x00, 0xEE,
+            0xF0, 0xFF, // 0x22C: sprite
+        ]);
+    }
+
+    #[test]
+    fn reports_errors() {
+        assert!(assemble(": main jump nowhere").unwrap_err().contains("nowhere"));
+        assert!(assemble(": main loop").unwrap_err().contains("again"));
+        assert!(assemble(": main v0 := 256").unwrap_err().contains("line 1"));
+        assert!(assemble(": main :macro foo { }").is_err());
+        assert!(assemble("v0 := 1").unwrap_err().contains("main"));
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::VecDeque;
+use std::time::{ Duration, Instant };
+
+use crate::vm::Registers;
+
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+const MAX_MESSAGES: usize = 3;
+
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+
+// a 3x5 font, each row's pixels in the low three bits with the leftmost highest. lower case
+// letters are drawn as upper case and anything else missing as a question mark
+const GLYPHS: [(char, [u8; GLYPH_HEIGHT]); 57] = [
+    (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+    ('\'', [0b010, 0b010, 0b000, 0b000, 0b000]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+    ('*', [0b000, 0b101, 0b010, 0b101, 0b000]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('<', [0b001, 0b010, 0b100, 0b010, 0b001]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('>', [0b100, 0b010, 0b001, 0b010, 0b100]),
+    ('?', [0b111, 0b001, 0b010, 0b000, 0b010]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    ('[', [0b011, 0b010, 0b010, 0b010, 0b011]),
+    (']', [0b110, 0b010, 0b010, 0b010, 0b110]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+];
+
+fn glyph(character: char) -> [u8; GLYPH_HEIGHT] {
+    let character = character.to_ascii_uppercase();
+    let found = GLYPHS.iter().find(|(glyph, _)| *glyph == character);
+    found.or_else(|| GLYPHS.iter().find(|(glyph, _)| *glyph == '?')).unwrap().1
+}
+
+/// The lit pixels of a line of text, in font pixels from its top left. Characters are a pixel
+/// apart.
+pub fn text_pixels(text: &str) -> Vec<(usize, usize)> {
+    let mut pixels = vec![];
+    for (column, character) in text.chars().enumerate() {
+        for (y, row) in glyph(character).iter().enumerate() {
+            for x in 0..GLYPH_WIDTH {
+                if row >> (GLYPH_WIDTH - 1 - x) & 1 == 1 {
+                    pixels.push((column * (GLYPH_WIDTH + 1) + x, y));
+                }
+            }
+        }
+    }
+    pixels
+}
+
+/// How wide a line of text is, in font pixels.
+pub fn text_width(text: &str) -> usize {
+    (text.chars().count() * (GLYPH_WIDTH + 1)).saturating_sub(1)
+}
+
+/// What the overlay shows besides notifications.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OsdMode {
+    #[default]
+    Off,
+    Stats, // frames and instructions a second
+    Debug, // the stats and the registers and timers
+}
+
+impl OsdMode {
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode {
+            "off" => Ok(Self::Off),
+            "stats" => Ok(Self::Stats),
+            "debug" => Ok(Self::Debug),
+            _ => Err(format!("unknown osd mode {}, expected off, stats or debug", mode)),
+        }
+    }
+
+    /// The next mode, for the hotkey that cycles through them.
+    pub fn next(&self) -> Self {
+        match self {
+            OsdMode::Off => OsdMode::Stats,
+            OsdMode::Stats => OsdMode::Debug,
+            OsdMode::Debug => OsdMode::Off,
+        }
+    }
+}
+
+/// The lines of text shown over the screen: the latest notifications, which fade after a couple
+/// of seconds, and whatever readouts the mode asks for.
+#[derive(Default)]
+pub struct Osd {
+    pub mode: OsdMode,
+    messages: VecDeque<(String, Instant)>, // and when they were shown
+    stats: Vec<String>,
+    error: Option<String>,
+}
+
+impl Osd {
+    pub fn new(mode: OsdMode) -> Self {
+        Self { mode, ..Self::default() }
+    }
+
+    pub fn notify(&mut self, message: impl Into<String>) {
+        self.notify_at(message, Instant::now());
+    }
+
+    pub fn notify_at(&mut self, message: impl Into<String>, now: Instant) {
+        self.messages.push_back((message.into(), now));
+        if self.messages.len() > MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+    }
+
+    /// An error to show above everything else until it's cleared, whatever the mode.
+    pub fn set_error(&mut self, error: Option<String>) {
+        self.error = error;
+    }
+
+    /// Updates the readouts. `ips` is instructions run a second.
+    pub fn set_stats(&mut self, fps: f64, ips: f64, registers: &Registers, sound_timer: u8) {
+        self.stats.clear();
+        if self.mode == OsdMode::Off {
+            return;
+        }
+        self.stats.push(format!("FPS {:.0} IPS {:.0}", fps, ips));
+        if self.mode == OsdMode::Debug {
+            let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<String>>().join(" ");
+            self.stats.push(format!("PC {:04X} I {:04X} DT {:02X} ST {:02X}", registers.pc, registers.index, registers.delay_timer, sound_timer));
+            self.stats.push(format!("V0-7 {}", hex(&registers.v[..8])));
+            self.stats.push(format!("V8-F {}", hex(&registers.v[8..])));
+        }
+    }
+
+    pub fn lines(&mut self) -> Vec<String> {
+        self.lines_at(Instant::now())
+    }
+
+    /// Any error, the readouts, then the notifications still showing at `now`.
+    pub fn lines_at(&mut self, now: Instant) -> Vec<String> {
+        self.messages.retain(|(_, shown)| now.duration_since(*shown) < MESSAGE_DURATION);
+        let messages = self.messages.iter().map(|(message, _)| message);
+        self.error.iter().chain(self.stats.iter()).chain(messages).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{ Duration, Instant };
+
+    use super::{ text_pixels, text_width, Osd, OsdMode };
+    use crate::vm::Registers;
+
+    #[test]
+    fn overlay() {
+        // the 1 then the lower case i, which is drawn as a capital, one pixel apart
+        let pixels = text_pixels("1i");
+        assert!(pixels.contains(&(1, 0)) && pixels.contains(&(0, 1)) && !pixels.contains(&(0, 0)));
+        assert!(pixels.contains(&(4, 0)) && pixels.contains(&(6, 0)) && !pixels.contains(&(4, 2)));
+        assert_eq!(text_width("1i"), 7);
+        assert_eq!(text_pixels("~"), text_pixels("?"));
+
+        let start = Instant::now();
+        let mut osd = Osd::new(OsdMode::Debug);
+        osd.set_stats(59.6, 700.0, &Registers { pc: 0x2A4, ..Registers::default() }, 3);
+        osd.notify_at("Paused", start);
+        osd.notify_at("Reset", start + Duration::from_secs(1));
+        let lines = osd.lines_at(start + Duration::from_millis(2500));
+        assert_eq!(lines, [
+            "FPS 60 IPS 700",
+            "PC 02A4 I 0000 DT 00 ST 03",
+            "V0-7 00 00 00 00 00 00 00 00",
+            "V8-F 00 00 00 00 00 00 00 00",
+            "Reset",
+        ]);
+
+        osd.mode = osd.mode.next();
+        osd.set_stats(60.0, 700.0, &Registers::default(), 0);
+        assert!(osd.lines_at(start + Duration::from_secs(5)).is_empty());
+        osd.set_error(Some("unknown opcode 0x5121 at 0x2A4".to_string()));
+        assert_eq!(osd.lines_at(start + Duration::from_secs(5)), ["unknown opcode 0x5121 at 0x2A4"]);
+    }
+}
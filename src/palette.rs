@@ -0,0 +1,101 @@
+/// An rgb colour.
+pub type Rgb = [u8; 3];
+
+/// The colours the screen is drawn in, indexed by which planes a pixel is lit on: 0 is the
+/// background and 1 the foreground, with 2 and 3 for the second plane and both planes together,
+/// and the rest for the 4 plane modes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub colors: [Rgb; 16],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::new(&[[0, 0, 0], [255, 255, 255]]).unwrap()
+    }
+}
+
+/// Built in palettes, in the order the theme hotkey steps through them.
+pub const THEMES: [(&str, &[&str]); 5] = [
+    ("classic", &["#000000", "#FFFFFF"]),
+    ("amber", &["#1A0F00", "#FFB000", "#B36B00", "#FFD866"]),
+    ("green", &["#001A05", "#33FF66", "#1A9933", "#B3FFC6"]),
+    ("octo", &["#996600", "#FFCC00", "#FF6600", "#662200"]),
+    ("lcd", &["#9BBC0F", "#0F380F", "#306230", "#8BAC0F"]),
+];
+
+impl Palette {
+    /// Builds a palette from 2, 4 or 16 colours, background first. Shorter palettes are repeated
+    /// to fill the rest.
+    pub fn new(colors: &[Rgb]) -> Result<Self, String> {
+        if ![2, 4, 16].contains(&colors.len()) {
+            return Err(format!("palettes have 2, 4 or 16 colours, not {}", colors.len()));
+        }
+        let mut palette = [[0; 3]; 16];
+        for (index, color) in palette.iter_mut().enumerate() {
+            *color = colors[index % colors.len()];
+        }
+        // with just two colours, anything lit is the foreground
+        if colors.len() == 2 {
+            palette[1..].fill(colors[1]);
+        }
+        Ok(Self { colors: palette })
+    }
+
+    /// Parses a list of `#rrggbb` colours.
+    pub fn parse<S: AsRef<str>>(colors: &[S]) -> Result<Self, String> {
+        let colors = colors.iter().map(|color| parse_color(color.as_ref())).collect::<Result<Vec<Rgb>, String>>()?;
+        Self::new(&colors)
+    }
+
+    pub fn theme(name: &str) -> Result<Self, String> {
+        match THEMES.iter().find(|(theme, _)| theme.eq_ignore_ascii_case(name)) {
+            Some((_, colors)) => Self::parse(colors),
+            None => {
+                let names: Vec<&str> = THEMES.iter().map(|(theme, _)| *theme).collect();
+                Err(format!("unknown theme {}, expected one of {}", name, names.join(", ")))
+            },
+        }
+    }
+
+    pub fn background(&self) -> Rgb {
+        self.colors[0]
+    }
+
+    pub fn foreground(&self) -> Rgb {
+        self.colors[1]
+    }
+}
+
+/// Parses a css style `#rrggbb` colour, with or without the `#`.
+pub fn parse_color(color: &str) -> Result<Rgb, String> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    let value = match u32::from_str_radix(hex, 16) {
+        Ok(value) if hex.len() == 6 => value,
+        _ => return Err(format!("{:?} is not a colour, expected #rrggbb", color)),
+    };
+    Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Palette, THEMES };
+
+    #[test]
+    fn palettes() {
+        let palette = Palette::parse(&["#102030", "#FFEEDD"]).unwrap();
+        assert_eq!(palette.background(), [0x10, 0x20, 0x30]);
+        assert!(palette.colors[1..].iter().all(|color| *color == [0xFF, 0xEE, 0xDD]));
+
+        let palette = Palette::theme("Octo").unwrap();
+        assert_eq!(palette.colors[2], [0xFF, 0x66, 0x00]);
+        assert_eq!(palette.colors[7], [0x66, 0x22, 0x00]);
+
+        assert!(Palette::parse(&["#000000"; 3]).is_err());
+        assert!(Palette::parse(&["#000000", "white"]).is_err());
+        assert!(Palette::theme("sepia").is_err());
+        for (name, _) in THEMES {
+            assert!(Palette::theme(name).is_ok());
+        }
+    }
+}
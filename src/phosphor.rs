@@ -0,0 +1,121 @@
+use crate::vm::{ Screen, SCREEN_WIDTH, SCREEN_HEIGHT };
+
+/// How brightly each pixel is lit, from 0 (background) to 1 (foreground).
+pub type Levels = [[f32; SCREEN_WIDTH]; SCREEN_HEIGHT];
+
+// levels below this are too dim to see and snap to the background
+const CUTOFF: f32 = 1.0 / 256.0;
+
+pub const DEFAULT_DECAY: f32 = 0.5;
+
+/// Ways of hiding the flicker from sprites being erased and redrawn every frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AntiFlicker {
+    #[default]
+    Off,
+    Or, // a pixel lit in either of the last two frames is lit
+    Decay(f32), // pixels fade out, keeping this fraction of their brightness each frame
+}
+
+impl AntiFlicker {
+    /// Parses `off`, `or`, `decay` or `decay:<rate>`, with the rate between 0 and 1.
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode.split_once(':') {
+            None if mode == "off" => Ok(Self::Off),
+            None if mode == "or" => Ok(Self::Or),
+            None if mode == "decay" => Ok(Self::Decay(DEFAULT_DECAY)),
+            Some(("decay", rate)) => match rate.parse::<f32>() {
+                Ok(rate) if (0.0..1.0).contains(&rate) => Ok(Self::Decay(rate)),
+                _ => Err(format!("invalid decay rate {}, expected a number from 0 up to 1", rate)),
+            },
+            _ => Err(format!("unknown anti-flicker mode {}, expected off, or, decay or decay:<rate>", mode)),
+        }
+    }
+}
+
+/// Turns the frames the vm draws into pixel brightnesses, blending in recent frames as the
+/// anti-flicker mode asks.
+pub struct Phosphor {
+    mode: AntiFlicker,
+    previous: Screen,
+    levels: Levels,
+}
+
+impl Phosphor {
+    pub fn new(mode: AntiFlicker) -> Self {
+        Self {
+            mode,
+            previous: [[false; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            levels: [[0.0; SCREEN_WIDTH]; SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn mode(&self) -> AntiFlicker {
+        self.mode
+    }
+
+    /// How brightly each pixel was shown in the last frame.
+    pub fn levels(&self) -> &Levels {
+        &self.levels
+    }
+
+    /// Takes the next frame and returns how brightly to show each pixel.
+    pub fn apply(&mut self, screen: &Screen) -> &Levels {
+        for (y, row) in screen.iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                let level = &mut self.levels[y][x];
+                *level = match self.mode {
+                    AntiFlicker::Off => *pixel as u8 as f32,
+                    AntiFlicker::Or => (*pixel || self.previous[y][x]) as u8 as f32,
+                    AntiFlicker::Decay(_) if *pixel => 1.0,
+                    AntiFlicker::Decay(rate) if *level * rate >= CUTOFF => *level * rate,
+                    AntiFlicker::Decay(_) => 0.0,
+                };
+            }
+        }
+        self.previous = *screen;
+        &self.levels
+    }
+
+    /// Same as `apply`, for displays that can only show a pixel on or off.
+    pub fn apply_lit(&mut self, screen: &Screen) -> Screen {
+        let mut lit = [[false; SCREEN_WIDTH]; SCREEN_HEIGHT];
+        for (lit, levels) in lit.iter_mut().zip(self.apply(screen).iter()) {
+            for (lit, level) in lit.iter_mut().zip(levels.iter()) {
+                *lit = *level >= 0.5;
+            }
+        }
+        lit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ AntiFlicker, Phosphor };
+    use crate::vm::VM;
+
+    #[test]
+    fn blends_frames() {
+        let mut lit = VM::create_screen();
+        lit[0][0] = true;
+        let blank = VM::create_screen();
+
+        let mut phosphor = Phosphor::new(AntiFlicker::Or);
+        assert_eq!(phosphor.apply(&lit)[0][0], 1.0);
+        assert_eq!(phosphor.apply(&blank)[0][0], 1.0);
+        assert_eq!(phosphor.apply(&blank)[0][0], 0.0);
+
+        let mut phosphor = Phosphor::new(AntiFlicker::parse("decay:0.25").unwrap());
+        assert_eq!(phosphor.apply(&lit)[0][0], 1.0);
+        assert_eq!(phosphor.apply(&blank)[0][0], 0.25);
+        assert_eq!(phosphor.apply(&blank)[0][0], 0.0625);
+        for _ in 0..4 {
+            phosphor.apply(&blank);
+        }
+        assert_eq!(phosphor.apply(&blank)[0][0], 0.0);
+
+        assert_eq!(AntiFlicker::parse("decay"), Ok(AntiFlicker::Decay(0.5)));
+        assert!(AntiFlicker::parse("decay:2").is_err());
+        assert!(AntiFlicker::parse("blur").is_err());
+    }
+}
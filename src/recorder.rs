@@ -0,0 +1,274 @@
+use std::fs::{ self, File };
+use std::io::{ BufWriter, Write };
+use std::path::{ Path, PathBuf };
+
+use gif::{ Encoder, Frame, Repeat };
+
+use crate::audio::WavWriter;
+use crate::filters::Image;
+use crate::palette::Palette;
+use crate::vm::{ Screen, SCREEN_WIDTH, SCREEN_HEIGHT };
+
+// recordings are scaled up so they're watchable without the player scaling them
+pub const RECORD_SCALE: usize = 4;
+const FRAMES_PER_SECOND: u64 = 60;
+// the shortest gif frame players show as it is, in centiseconds
+const MIN_DELAY: u64 = 2;
+
+/// What gameplay is recorded to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RecordFormat {
+    #[default]
+    Gif, // an animated gif in the palette's exact colours, with repeated frames merged
+    Y4m, // uncompressed yuv4mpeg video, every frame, with the beeper in a wav file alongside
+}
+
+impl RecordFormat {
+    pub fn parse(format: &str) -> Result<Self, String> {
+        match format {
+            "gif" => Ok(Self::Gif),
+            "y4m" => Ok(Self::Y4m),
+            _ => Err(format!("unknown recording format {}, expected gif or y4m", format)),
+        }
+    }
+
+    /// The format a path's extension asks for.
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let extension = path.extension().map_or(String::new(), |extension| extension.to_string_lossy().to_lowercase());
+        Self::parse(&extension).map_err(|_| format!("unable to record to {}, expected a .gif or .y4m file", path.display()))
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordFormat::Gif => "gif",
+            RecordFormat::Y4m => "y4m",
+        }
+    }
+}
+
+enum Output {
+    Gif(Encoder<BufWriter<File>>),
+    Y4m(BufWriter<File>, WavWriter),
+}
+
+/// Records the frames the io loop shows, one call to `frame` per 60th of a second.
+pub struct Recorder {
+    path: PathBuf,
+    output: Output,
+    palette: Palette, // the gif's global palette; frames in other colours get their own
+    pending: Option<(Screen, Palette, u64)>, // a gif frame held back while it repeats, and for how many frames
+    frames: u64,
+    written_centiseconds: u64,
+}
+
+impl Recorder {
+    /// Starts a recording at `path`, in the format its extension asks for.
+    pub fn create(path: &Path, palette: Palette) -> Result<Self, String> {
+        let format = RecordFormat::from_path(path)?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|error| format!("unable to create {}: {}", dir.display(), error))?;
+        }
+        let file = File::create(path).map_err(|error| format!("unable to create {}: {}", path.display(), error))?;
+        let mut file = BufWriter::new(file);
+        let (width, height) = (SCREEN_WIDTH * RECORD_SCALE, SCREEN_HEIGHT * RECORD_SCALE);
+
+        let output = match format {
+            RecordFormat::Gif => {
+                let colors = [palette.background(), palette.foreground()].concat();
+                let mut encoder = Encoder::new(file, width as u16, height as u16, &colors).map_err(|error| error.to_string())?;
+                encoder.set_repeat(Repeat::Infinite).map_err(|error| error.to_string())?;
+                Output::Gif(encoder)
+            },
+            RecordFormat::Y4m => {
+                writeln!(file, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444 XCOLORRANGE=FULL", width, height, FRAMES_PER_SECOND).map_err(|error| error.to_string())?;
+                Output::Y4m(file, WavWriter::create(&path.with_extension("wav"))?)
+            },
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            output,
+            palette,
+            pending: None,
+            frames: 0,
+            written_centiseconds: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Adds a frame, and the audio rendered alongside it.
+    pub fn frame(&mut self, screen: &Screen, palette: &Palette, samples: &[f32]) -> Result<(), String> {
+        let short = self.pending.as_ref().is_some_and(|(_, _, repeats)| self.frames_delay(*repeats) < MIN_DELAY);
+        match &mut self.output {
+            Output::Gif(_) => match &mut self.pending {
+                Some((pending_screen, pending_palette, repeats)) if pending_screen == screen && pending_palette == palette => *repeats += 1,
+                // players show anything under 2 centiseconds for 10, so a frame that would be
+                // that short has the next laid over it instead, as the eye would
+                Some((pending_screen, pending_palette, repeats)) if short => {
+                    for (pending_row, row) in pending_screen.iter_mut().zip(screen) {
+                        for (pending, pixel) in pending_row.iter_mut().zip(row) {
+                            *pending |= *pixel;
+                        }
+                    }
+                    *pending_palette = *palette;
+                    *repeats += 1;
+                },
+                _ => {
+                    self.flush_gif()?;
+                    self.pending = Some((*screen, *palette, 1));
+                },
+            },
+            Output::Y4m(file, wav) => {
+                let image = Image::from_screen(screen, palette, RECORD_SCALE);
+                let (y, u, v) = yuv_planes(&image);
+                file.write_all(b"FRAME\n")
+                    .and_then(|_| file.write_all(&y))
+                    .and_then(|_| file.write_all(&u))
+                    .and_then(|_| file.write_all(&v))
+                    .map_err(|error| format!("unable to write {}: {}", self.path.display(), error))?;
+                wav.write(samples)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Writes out anything held back and closes the files.
+    pub fn finish(mut self) -> Result<PathBuf, String> {
+        self.flush_gif()?;
+        let result = match self.output {
+            Output::Gif(encoder) => encoder.into_inner().and_then(|mut file| file.flush()).map(|_| None),
+            Output::Y4m(mut file, wav) => file.flush().map(|_| Some(wav)),
+        };
+        let wav = result.map_err(|error| format!("unable to write {}: {}", self.path.display(), error))?;
+        if let Some(wav) = wav {
+            wav.finish()?;
+        }
+        Ok(self.path)
+    }
+
+    // the delay, in centiseconds, a gif frame held for `repeats` frames from here would get
+    fn frames_delay(&self, repeats: u64) -> u64 {
+        (self.frames + repeats) * 100 / FRAMES_PER_SECOND - self.written_centiseconds
+    }
+
+    // writes the held back gif frame, shown for as long as it repeated. gif delays are in 100ths
+    // of a second, so they're worked out from the total time to keep rounding from drifting
+    fn flush_gif(&mut self) -> Result<(), String> {
+        let (screen, palette, repeats) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        // only the last frame can still be short, and it gets the little extra
+        let delay = self.frames_delay(repeats).max(MIN_DELAY);
+        self.frames += repeats;
+        self.written_centiseconds = self.frames * 100 / FRAMES_PER_SECOND;
+        let encoder = match &mut self.output {
+            Output::Gif(encoder) => encoder,
+            Output::Y4m(..) => return Ok(()),
+        };
+
+        let (width, height) = (SCREEN_WIDTH * RECORD_SCALE, SCREEN_HEIGHT * RECORD_SCALE);
+        let mut indices = vec![0; width * height];
+        for (y, row) in indices.chunks_mut(width).enumerate() {
+            for (x, index) in row.iter_mut().enumerate() {
+                *index = screen[y / RECORD_SCALE][x / RECORD_SCALE] as u8;
+            }
+        }
+        let mut frame = Frame::from_indexed_pixels(width as u16, height as u16, indices, None);
+        frame.delay = delay as u16;
+        if palette != self.palette {
+            frame.palette = Some([palette.background(), palette.foreground()].concat());
+        }
+        encoder.write_frame(&frame).map_err(|error| format!("unable to write {}: {}", self.path.display(), error))
+    }
+}
+
+// bt.601 full range. players take yuv4mpeg to be limited range unless the header says otherwise
+fn yuv_planes(image: &Image) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let pixels = image.width * image.height;
+    let (mut y, mut u, mut v) = (Vec::with_capacity(pixels), Vec::with_capacity(pixels), Vec::with_capacity(pixels));
+    for rgb in image.pixels.chunks(3) {
+        let (r, g, b) = (rgb[0] as f32, rgb[1] as f32, rgb[2] as f32);
+        y.push((0.299 * r + 0.587 * g + 0.114 * b).round() as u8);
+        u.push((128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b).round().clamp(0.0, 255.0) as u8);
+        v.push((128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b).round().clamp(0.0, 255.0) as u8);
+    }
+    (y, u, v)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use gif::{ ColorOutput, DecodeOptions };
+
+    use super::{ Recorder, RECORD_SCALE };
+    use crate::palette::Palette;
+    use crate::vm::VM;
+
+    #[test]
+    fn records_gif_and_y4m() {
+        let blank = VM::create_screen();
+        let mut lit = VM::create_screen();
+        lit[0][0] = true;
+        let palette = Palette::parse(&["#102030", "#FFEEDD"]).unwrap();
+        let dir = env::temp_dir().join(format!("chip-8-rs-recorder-{}", std::process::id()));
+
+        // a second of blank screen then half a second lit: two frames, a second and half apart
+        let mut recorder = Recorder::create(&dir.join("test.gif"), palette).unwrap();
+        for screen in [blank; 60].iter().chain([lit; 30].iter()) {
+            recorder.frame(screen, &palette, &[]).unwrap();
+        }
+        let path = recorder.finish().unwrap();
+
+        let mut options = DecodeOptions::new();
+        options.set_color_output(ColorOutput::Indexed);
+        let mut decoder = options.read_info(fs::File::open(path).unwrap()).unwrap();
+        assert_eq!(decoder.global_palette().unwrap(), [0x10, 0x20, 0x30, 0xFF, 0xEE, 0xDD]);
+        let mut frames = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer[0]));
+        }
+        assert_eq!(frames, [(100, 0), (50, 1)]);
+
+        // a sprite flickering every frame is never shown for less than 2 centiseconds, but the
+        // gif still lasts as long as the frames did
+        let mut recorder = Recorder::create(&dir.join("flicker.gif"), palette).unwrap();
+        for screen in [blank, lit].iter().cycle().take(31) {
+            recorder.frame(screen, &palette, &[]).unwrap();
+        }
+        let path = recorder.finish().unwrap();
+        let mut options = DecodeOptions::new();
+        options.set_color_output(ColorOutput::Indexed);
+        let mut decoder = options.read_info(fs::File::open(path).unwrap()).unwrap();
+        let mut delays = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        assert!(delays.iter().all(|delay| *delay >= 2));
+        assert_eq!(delays.iter().sum::<u16>(), 52);
+
+        let mut recorder = Recorder::create(&dir.join("test.y4m"), palette).unwrap();
+        recorder.frame(&lit, &palette, &[0.5; 735]).unwrap();
+        recorder.frame(&blank, &palette, &[0.0; 735]).unwrap();
+        recorder.finish().unwrap();
+
+        let video = fs::read(dir.join("test.y4m")).unwrap();
+        let header = b"YUV4MPEG2 W256 H128 F60:1 Ip A1:1 C444 XCOLORRANGE=FULL\n";
+        let frame_size = b"FRAME\n".len() + 3 * 64 * 32 * RECORD_SCALE * RECORD_SCALE;
+        assert!(video.starts_with(header));
+        assert_eq!(video.len(), header.len() + 2 * frame_size);
+
+        let audio = fs::read(dir.join("test.wav")).unwrap();
+        assert_eq!(audio.len(), 44 + 2 * 735 * 2);
+        assert_eq!(audio[40..44], (2u32 * 735 * 2).to_le_bytes());
+        assert_eq!(audio[44..46], 16383i16.to_le_bytes());
+        assert!(audio[44 + 735 * 2..].iter().all(|byte| *byte == 0));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::io::{ Cursor, Read, stdin };
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use crate::cartridge::{ self, Options };
+use crate::octo;
+use crate::vm::MEMORY_BYTES;
+
+pub const DEFAULT_LOAD_ADDRESS: u16 = 0x200;
+
+// the address the eti-660 loads its programs at
+pub const ETI_660_LOAD_ADDRESS: u16 = 0x600;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+
+// files in an archive that look like roms, picked over anything else in there
+const ROM_EXTENSIONS: [&str; 4] = ["ch8", "c8", "rom", "bin"];
+
+/// A tick rate in instructions per frame, as Octo cartridges and the rom database give it, as
+/// instructions per second at 60 frames a second. A rate of 0 is taken as none at all.
+pub fn ticks_per_second(tickrate: Option<u32>) -> Option<u64> {
+    tickrate.filter(|tickrate| *tickrate > 0).map(|tickrate| tickrate as u64 * 60)
+}
+
+/// A loaded rom, along with the settings it came with if it was an Octo cartridge.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rom {
+    pub bytes: Vec<u8>,
+    pub options: Option<Options>,
+}
+
+/// Reads roms and checks they fit in memory above the load address. Roms can come from a file,
+/// stdin or a buffer, and can be gzipped, in a zip archive, Octo cartridges or Octo source.
+#[derive(Clone, Copy, Debug)]
+pub struct RomLoader {
+    load_address: u16,
+}
+
+impl Default for RomLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RomLoader {
+    pub fn new() -> Self {
+        Self {
+            load_address: DEFAULT_LOAD_ADDRESS,
+        }
+    }
+
+    pub fn set_load_address(&mut self, load_address: u16) -> Result<(), String> {
+        if load_address as usize >= MEMORY_BYTES {
+            return Err(format!("load address {:#05X} is outside of memory", load_address));
+        }
+        self.load_address = load_address;
+        Ok(())
+    }
+
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+
+    /// The largest rom that fits between the load address and the end of memory.
+    pub fn max_size(&self) -> usize {
+        MEMORY_BYTES - self.load_address as usize
+    }
+
+    /// Reads the rom at `path`, or stdin if the path is `-`. `.8o` files are assembled.
+    pub fn load_path(&self, path: &Path) -> Result<Rom, String> {
+        if path == Path::new("-") {
+            return self.load_reader(stdin().lock());
+        }
+        let bytes = fs::read(path).map_err(|error| format!("unable to read {}: {}", path.display(), error))?;
+        let rom = match path.extension() {
+            Some(extension) if extension == "8o" => self.load_source(&String::from_utf8_lossy(&bytes)),
+            _ => self.load_bytes(&bytes),
+        };
+        rom.map_err(|error| format!("{}: {}", path.display(), error))
+    }
+
+    pub fn load_reader<R: Read>(&self, mut reader: R) -> Result<Rom, String> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).map_err(|error| format!("unable to read rom: {}", error))?;
+        self.load_bytes(&bytes)
+    }
+
+    /// Unpacks `bytes` if they're an archive or cartridge and checks the rom fits.
+    pub fn load_bytes(&self, bytes: &[u8]) -> Result<Rom, String> {
+        if cartridge::is_cartridge(bytes) {
+            let cartridge = cartridge::load(bytes)?;
+            let mut rom = self.load_source(&cartridge.program)?;
+            rom.options = Some(cartridge.options);
+            return Ok(rom);
+        }
+
+        let bytes = if bytes.starts_with(&GZIP_MAGIC) {
+            Self::gunzip(bytes)?
+        } else if bytes.starts_with(&ZIP_MAGIC) {
+            Self::unzip(bytes)?
+        } else {
+            bytes.to_vec()
+        };
+        self.validate(&bytes)?;
+        Ok(Rom { bytes, options: None })
+    }
+
+    /// Assembles Octo source.
+    pub fn load_source(&self, source: &str) -> Result<Rom, String> {
+        if self.load_address != DEFAULT_LOAD_ADDRESS {
+            return Err(format!("octo programs only run from {:#05X}", DEFAULT_LOAD_ADDRESS));
+        }
+        let bytes = octo::assemble(source)?;
+        self.validate(&bytes)?;
+        Ok(Rom { bytes, options: None })
+    }
+
+    pub fn validate(&self, rom: &[u8]) -> Result<(), String> {
+        if rom.is_empty() {
+            return Err("rom is empty".to_string());
+        }
+        if rom.len() > self.max_size() {
+            return Err(format!(
+                "rom is {} bytes, only {} fit when loading at {:#05X}",
+                rom.len(),
+                self.max_size(),
+                self.load_address,
+            ));
+        }
+        Ok(())
+    }
+
+    fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let mut rom = vec![];
+        GzDecoder::new(bytes)
+            .read_to_end(&mut rom)
+            .map_err(|error| format!("invalid gzip file: {}", error))?;
+        Ok(rom)
+    }
+
+    // takes the first file with a rom extension, or failing that the first file, in the order
+    // they're stored in the archive
+    fn unzip(bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(|error| format!("invalid zip file: {}", error))?;
+
+        let mut names = vec![];
+        for index in 0..archive.len() {
+            let file = archive.by_index_raw(index).map_err(|error| format!("invalid zip file: {}", error))?;
+            if !file.is_dir() {
+                names.push((index, file.name().to_string()));
+            }
+        }
+        let mut names_by_preference = names.iter().filter(|(_, name)| {
+            let extension = Path::new(name).extension().map(|extension| extension.to_string_lossy().to_lowercase());
+            extension.is_some_and(|extension| ROM_EXTENSIONS.contains(&extension.as_str()))
+        });
+        let (index, name) = names_by_preference.next().or(names.first()).ok_or("zip file is empty")?;
+
+        let mut file = archive.by_index(*index).map_err(|error| format!("unable to read {} from zip file: {}", name, error))?;
+        let mut rom = vec![];
+        file.read_to_end(&mut rom).map_err(|error| format!("unable to read {} from zip file: {}", name, error))?;
+        Ok(rom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ Cursor, Write };
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+
+    use super::{ RomLoader, ETI_660_LOAD_ADDRESS, ticks_per_second };
+
+    const ROM: [u8; 4] = [0x60, 0x05, 0x12, 0x02];
+
+    #[test]
+    fn archives() {
+        let loader = RomLoader::new();
+        assert_eq!(loader.load_bytes(&ROM).unwrap().bytes, ROM);
+
+        let mut gzip = GzEncoder::new(vec![], Compression::default());
+        gzip.write_all(&ROM).unwrap();
+        assert_eq!(loader.load_bytes(&gzip.finish().unwrap()).unwrap().bytes, ROM);
+
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        zip.start_file("readme.txt", FileOptions::default()).unwrap();
+        zip.write_all(b"not a rom").unwrap();
+        zip.start_file("games/spin.ch8", FileOptions::default()).unwrap();
+        zip.write_all(&ROM).unwrap();
+        // later roms lose out to the first, however the archive's names happen to hash
+        for name in ["a.ch8", "b.ch8", "c.ch8", "d.ch8", "e.ch8", "f.ch8", "g.ch8", "h.ch8"] {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(&[0x00, 0xE0]).unwrap();
+        }
+        assert_eq!(loader.load_bytes(&zip.finish().unwrap().into_inner()).unwrap().bytes, ROM);
+    }
+
+    #[test]
+    fn validates_size() {
+        let mut loader = RomLoader::new();
+        assert!(loader.load_bytes(&[]).is_err());
+        assert!(loader.load_bytes(&[0; 3584]).is_ok());
+        assert!(loader.load_bytes(&[0; 3585]).is_err());
+
+        loader.set_load_address(ETI_660_LOAD_ADDRESS).unwrap();
+        assert!(loader.load_bytes(&[0; 2560]).is_ok());
+        assert!(loader.load_bytes(&[0; 2561]).is_err());
+        assert!(loader.set_load_address(0x1000).is_err());
+    }
+
+    #[test]
+    fn tick_rates() {
+        assert_eq!(ticks_per_second(Some(15)), Some(900));
+        assert_eq!(ticks_per_second(Some(0)), None);
+        assert_eq!(ticks_per_second(None), None);
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::keymap::KeyMap;
+use crate::palette::Palette;
+use crate::rom;
+use crate::vm::Quirks;
+
+/// Named game actions ("up", "a", "player2Left"...) and the chip-8 key each one is on, as the
+/// database lists them.
+pub type ActionKeys = BTreeMap<String, u8>;
+
+// the platforms this vm can run, best first. a rom listed for several is run as the first of these
+const SUPPORTED_PLATFORMS: [&str; 3] = ["originalChip8", "hybridVIP", "modernChip8"];
+
+/// Everything the database knows about one rom, resolved for the platform it'll run as.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub platform: String,
+    pub supported: bool, // false if none of the rom's platforms can be run, in which case the rest is chip-8's defaults
+    pub quirks: Quirks,
+    pub tickrate: Option<u32>, // instructions per frame
+    pub start_address: Option<u16>,
+    pub keys: ActionKeys,
+    pub palette: Option<Palette>,
+}
+
+impl RomInfo {
+    /// The tick rate as instructions per second, see `rom::ticks_per_second`.
+    pub fn ticks_per_second(&self) -> Option<u64> {
+        rom::ticks_per_second(self.tickrate)
+    }
+}
+
+/// Metadata for known roms, keyed by the sha1 of their bytes. Reads a checkout of the community
+/// chip-8-database (https://github.com/chip-8/chip-8-database), i.e. a directory holding its
+/// `sha1-hashes.json`, `programs.json` and `platforms.json`.
+pub struct RomDatabase {
+    hashes: HashMap<String, usize>,
+    programs: Vec<Program>,
+    platforms: Vec<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, DatabaseQuirks>,
+    tickrate: Option<u32>,
+    start_address: Option<u16>,
+    #[serde(default)]
+    keys: ActionKeys,
+    colors: Option<Colors>,
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Platform {
+    id: String,
+    default_tickrate: Option<u32>,
+    #[serde(default)]
+    quirks: DatabaseQuirks,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DatabaseQuirks {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl DatabaseQuirks {
+    // lays the quirks that are set over `quirks`
+    fn apply(&self, quirks: &mut Quirks) {
+        let fields = [
+            (self.shift, &mut quirks.shift),
+            (self.memory_increment_by_x, &mut quirks.memory_increment_by_x),
+            (self.memory_leave_i_unchanged, &mut quirks.memory_leave_i_unchanged),
+            (self.wrap, &mut quirks.wrap),
+            (self.jump, &mut quirks.jump),
+            (self.vblank, &mut quirks.vblank),
+            (self.logic, &mut quirks.logic),
+        ];
+        for (value, quirk) in fields {
+            if let Some(value) = value {
+                *quirk = value;
+            }
+        }
+    }
+}
+
+impl RomDatabase {
+    pub fn load(directory: &Path) -> Result<Self, String> {
+        Ok(Self {
+            hashes: Self::read(&directory.join("sha1-hashes.json"))?,
+            programs: Self::read(&directory.join("programs.json"))?,
+            platforms: Self::read(&directory.join("platforms.json"))?,
+        })
+    }
+
+    fn read<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+        let text = fs::read_to_string(path).map_err(|error| format!("unable to read {}: {}", path.display(), error))?;
+        Self::parse(&text).map_err(|error| format!("invalid rom database {}: {}", path.display(), error))
+    }
+
+    fn parse<T: DeserializeOwned>(text: &str) -> Result<T, String> {
+        serde_json::from_str(text).map_err(|error| error.to_string())
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<RomInfo> {
+        let hash = sha1_smol::Sha1::from(rom).digest().to_string();
+        let program = self.programs.get(*self.hashes.get(&hash)?)?;
+        let rom = program.roms.get(&hash)?;
+
+        let supported = rom.platforms.iter().find(|platform| SUPPORTED_PLATFORMS.contains(&platform.as_str()));
+        let platform = supported.or(rom.platforms.first())?;
+
+        // an unsupported platform's quirks and speed would only half make it work
+        let mut quirks = Quirks::default();
+        let known = self.platforms.iter().find(|known| &known.id == platform).filter(|_| supported.is_some());
+        if let Some(known) = known {
+            known.quirks.apply(&mut quirks);
+        }
+        if let Some(rom_quirks) = rom.quirky_platforms.get(platform).filter(|_| supported.is_some()) {
+            rom_quirks.apply(&mut quirks);
+        }
+
+        // pixels are listed background first, then foreground
+        let palette = rom.colors.as_ref().and_then(|colors| Palette::parse(&colors.pixels).ok());
+
+        Some(RomInfo {
+            title: program.title.clone(),
+            platform: platform.clone(),
+            supported: supported.is_some(),
+            quirks,
+            // a rate of 0 means none given, so the platform's is used instead
+            tickrate: rom.tickrate.filter(|tickrate| *tickrate > 0 && supported.is_some())
+                .or(known.and_then(|known| known.default_tickrate)),
+            start_address: rom.start_address,
+            keys: rom.keys.clone(),
+            palette,
+        })
+    }
+}
+
+/// Adds host keys for the database's game actions on top of `map`: the arrow keys for movement
+/// and space/return for the buttons, with ijkl and u/o for a second player.
+pub fn bind_keyboard_actions(map: &mut KeyMap, actions: &ActionKeys) {
+    bind_actions(map, actions, &[
+        ("up", &["Up"]),
+        ("down", &["Down"]),
+        ("left", &["Left"]),
+        ("right", &["Right"]),
+        ("a", &["Space"]),
+        ("b", &["Return"]),
+        ("player2Up", &["I"]),
+        ("player2Down", &["K"]),
+        ("player2Left", &["J"]),
+        ("player2Right", &["L"]),
+        ("player2A", &["U"]),
+        ("player2B", &["O"]),
+    ]);
+}
+
+/// Same as `bind_keyboard_actions` for game controllers, with the d-pad and left stick for
+/// movement.
+pub fn bind_controller_actions(map: &mut KeyMap, actions: &ActionKeys) {
+    bind_actions(map, actions, &[
+        ("up", &["dpup", "lefty-"]),
+        ("down", &["dpdown", "lefty+"]),
+        ("left", &["dpleft", "leftx-"]),
+        ("right", &["dpright", "leftx+"]),
+        ("a", &["a"]),
+        ("b", &["b"]),
+    ]);
+}
+
+fn bind_actions(map: &mut KeyMap, actions: &ActionKeys, hosts: &[(&str, &[&str])]) {
+    for (action, hosts) in hosts {
+        if let Some(key) = actions.get(*action).filter(|key| **key < 16) {
+            // clear the host keys from wherever they were so they only press the action's key
+            for host in hosts.iter() {
+                map.unbind_host(host);
+                map.add(*key, host.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ RomDatabase, bind_keyboard_actions };
+    use crate::keymap::KeyMap;
+    use crate::palette::Palette;
+
+    #[test]
+    fn lookup_by_hash() {
+        let rom = [0x12, 0x00];
+        let hash = sha1_smol::Sha1::from(rom).digest().to_string();
+        let database = RomDatabase {
+            hashes: RomDatabase::parse(&format!(r#"{{ "{}": 0 }}"#, hash)).unwrap(),
+            programs: RomDatabase::parse(&format!(r##"[{{
+                "title": "Spin",
+                "roms": {{ "{}": {{
+                    "platforms": ["superchip", "modernChip8"],
+                    "quirkyPlatforms": {{ "modernChip8": {{ "wrap": true }} }},
+                    "startAddress": 1536,
+                    "keys": {{ "up": 5, "a": 6 }},
+                    "colors": {{ "pixels": ["#102030", "#ffeedd"] }}
+                }} }}
+            }}]"##, hash)).unwrap(),
+            platforms: RomDatabase::parse(r#"[{
+                "id": "modernChip8",
+                "defaultTickrate": 12,
+                "quirks": { "shift": true, "memoryLeaveIUnchanged": false, "wrap": false }
+            }]"#).unwrap(),
+        };
+
+        let info = database.lookup(&rom).unwrap();
+        assert_eq!(info.title, "Spin");
+        assert_eq!(info.platform, "modernChip8");
+        assert!(info.quirks.shift && info.quirks.wrap && !info.quirks.memory_leave_i_unchanged);
+        assert_eq!(info.ticks_per_second(), Some(720));
+        assert_eq!(info.start_address, Some(0x600));
+        assert_eq!(info.palette, Some(Palette::parse(&["#102030", "#ffeedd"]).unwrap()));
+        assert!(database.lookup(&[0x00, 0xE0]).is_none());
+        assert!(info.supported);
+
+        let mut map = KeyMap::default();
+        bind_keyboard_actions(&mut map, &info.keys);
+        assert_eq!(map.keys_for("Up"), 1 << 0x5);
+        assert_eq!(map.keys_for("Space"), 1 << 0x6);
+        assert_eq!(map.keys_for("W"), 1 << 0x5);
+    }
+
+    #[test]
+    fn unsupported_and_unset() {
+        let roms = [[0x12, 0x00], [0x00, 0xE0]];
+        let hashes: Vec<String> = roms.iter().map(|rom| sha1_smol::Sha1::from(rom).digest().to_string()).collect();
+        let database = RomDatabase {
+            hashes: RomDatabase::parse(&format!(r#"{{ "{}": 0, "{}": 0 }}"#, hashes[0], hashes[1])).unwrap(),
+            programs: RomDatabase::parse(&format!(r##"[{{
+                "title": "Odd",
+                "roms": {{
+                    "{}": {{ "platforms": ["superchip", "xochip"], "tickrate": 30 }},
+                    "{}": {{ "platforms": ["modernChip8"], "tickrate": 0 }}
+                }}
+            }}]"##, hashes[0], hashes[1])).unwrap(),
+            platforms: RomDatabase::parse(r#"[
+                { "id": "superchip", "quirks": { "wrap": true } },
+                { "id": "modernChip8", "defaultTickrate": 12 }
+            ]"#).unwrap(),
+        };
+
+        // listed, but as plain chip-8 rather than with superchip's quirks and speed
+        let info = database.lookup(&roms[0]).unwrap();
+        assert_eq!(info.platform, "superchip");
+        assert!(!info.supported);
+        assert_eq!(info.quirks, Default::default());
+        assert_eq!(info.ticks_per_second(), None);
+
+        // a rom with a tick rate of 0 runs at its platform's
+        assert_eq!(database.lookup(&roms[1]).unwrap().ticks_per_second(), Some(720));
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use png::{ BitDepth, ColorType, Encoder };
+
+use crate::filters::Image;
+use crate::palette::Palette;
+use crate::vm::Screen;
+
+/// What a screenshot captures.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ScreenshotMode {
+    #[default]
+    Display, // the screen as it's shown, at the window's scale with any filters
+    Native, // a pixel to a pixel, in the palette's colours
+}
+
+impl ScreenshotMode {
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode {
+            "display" => Ok(Self::Display),
+            "native" => Ok(Self::Native),
+            _ => Err(format!("unknown screenshot mode {}, expected display or native", mode)),
+        }
+    }
+}
+
+/// The screen a pixel to a pixel, for frontends that can't capture what they show.
+pub fn native(screen: &Screen, palette: &Palette) -> Image {
+    Image::from_screen(screen, palette, 1)
+}
+
+pub fn encode_png(image: &Image) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    {
+        let mut encoder = Encoder::new(&mut bytes, image.width as u32, image.height as u32);
+        encoder.set_color(ColorType::Rgb);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|error| error.to_string())?;
+        writer.write_image_data(&image.pixels).map_err(|error| error.to_string())?;
+    }
+    Ok(bytes)
+}
+
+/// Writes the image to a timestamped png in `dir` and returns its path.
+pub fn save(dir: &Path, image: &Image) -> Result<PathBuf, String> {
+    let path = timestamped_path(dir, "png");
+    let bytes = encode_png(image)?;
+    fs::create_dir_all(dir).map_err(|error| format!("unable to create {}: {}", dir.display(), error))?;
+    fs::write(&path, bytes).map_err(|error| format!("unable to write {}: {}", path.display(), error))?;
+    Ok(path)
+}
+
+/// A file in `dir` named after the current time, e.g. `chip-8-rs-20240131-235959-123.png`.
+pub fn timestamped_path(dir: &Path, extension: &str) -> PathBuf {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    dir.join(format!("chip-8-rs-{}.{}", timestamp(now.as_secs(), now.subsec_millis()), extension))
+}
+
+// utc, from the days since 1970 to a calendar date as in http://howardhinnant.github.io/date_algorithms.html
+fn timestamp(seconds: u64, millis: u32) -> String {
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    let time = seconds % 86400;
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}", year, month, day, time / 3600, time / 60 % 60, time % 60, millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ encode_png, native, timestamp };
+    use crate::palette::Palette;
+    use crate::vm::VM;
+
+    #[test]
+    fn encodes_screen() {
+        let mut screen = VM::create_screen();
+        screen[1][2] = true;
+        let palette = Palette::parse(&["#102030", "#FFEEDD"]).unwrap();
+        let png = encode_png(&native(&screen, &palette)).unwrap();
+
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (64, 32));
+        assert_eq!(pixels[..3], [0x10, 0x20, 0x30]);
+        let offset = (64 + 2) * 3;
+        assert_eq!(pixels[offset..offset + 3], [0xFF, 0xEE, 0xDD]);
+
+        assert_eq!(timestamp(0, 5), "19700101-000000-005");
+        assert_eq!(timestamp(1709251199, 999), "20240229-235959-999");
+    }
+}
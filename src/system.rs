@@ -1,29 +1,145 @@
-use std::fs::{File, self};
-use std::io::Read;
+use std::fs;
+use std::fmt;
+use std::path::{ Path, PathBuf };
 use std::sync::{Arc, RwLock};
 use std::sync::mpsc::{Sender, Receiver, channel, TryRecvError};
 use std::thread::{JoinHandle, self};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use rodio::source::SineWave;
-use rodio::{OutputStream, Sink, Source};
-use sdl2::event::Event;
-use sdl2::keyboard::Scancode;
-
-use super::vm::{ VM, Screen, Status };
-use super::io::IO;
+use super::memory::MemoryMap;
+use super::vm::{ VM, Registers, Screen, Status, Quirks, StackConfig, VmError };
+use super::audio::{ AudioRenderer, SoundEvent, Tone, WavWriter };
+use super::io;
+use super::launcher::RecentFiles;
+use super::frontend::Frontend;
+use super::palette::{ Palette, THEMES };
+use super::osd::{ Osd, OsdMode };
+use super::phosphor::AntiFlicker;
+use super::recorder::{ Recorder, RecordFormat };
+use super::rom::{ Rom, RomLoader, DEFAULT_LOAD_ADDRESS };
+use super::romdb::{ ActionKeys, RomDatabase };
+use super::screenshot;
+use super::threaded::ThreadedBackend;
 
 enum Signal {
     DecrementDelayTimer,
     DecrementSoundTimer,
     Terminate,
     SendKeys(u16),
+    SetSpeed(f64),
+    AdvanceFrame,
+    Reset,
+    Load(Vec<u8>, Setup, bool), // a rom to reset into in place of the running one, and whether to replay the input so far
+}
+
+// what a rom runs with, besides the rom itself
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Setup {
+    quirks: Quirks,
+    ticks_per_second: u64,
+    load_address: u16,
+}
+
+// speeds for the fast forward and slow motion hotkeys
+const FAST_FORWARD: f64 = 4.0;
+const SLOW_MOTION: f64 = 0.25;
+
+// how many frames go by between checks on a watched rom
+const WATCH_INTERVAL: u64 = 15;
+
+// how often the io loop runs a frame, and the timers count down
+const FRAMES_PER_SECOND: u64 = 60;
+
+const DEFAULT_TICKS_PER_SECOND: u64 = 700;
+
+// how the vm is being run, as the io loop's signals ask
+struct Pacing {
+    speed: f64, // 1 for full speed, 0 for paused
+    frame_budget: u64, // instructions left to run while paused, after a frame advance
+    instructions_per_second: u64,
+    owed: u64, // the part of an instruction left over from the frames so far, in 60ths
+}
+
+impl Pacing {
+    // how many instructions the next frame runs. most instruction rates don't divide into 60,
+    // so what's left over is carried into the following frames and a second runs every one
+    fn next_frame(&mut self) -> u64 {
+        self.owed += self.instructions_per_second;
+        let instructions = self.owed / FRAMES_PER_SECOND;
+        self.owed %= FRAMES_PER_SECOND;
+        instructions
+    }
+}
+
+/// What's kept when a watched rom is reloaded. Without either it starts afresh.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Watch {
+    pub keep_seed: bool, // every reload draws the same random numbers
+    pub replay: bool, // the keys pressed so far are played back into the new rom, frame by frame
+}
+
+impl Watch {
+    /// Parses a comma separated list of `seed` and `replay`.
+    pub fn parse(options: &str) -> Result<Self, String> {
+        let mut watch = Self::default();
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            match option {
+                "seed" => watch.keep_seed = true,
+                "replay" => watch.replay = true,
+                _ => return Err(format!("unknown watch option {}, expected seed or replay", option)),
+            }
+        }
+        Ok(watch)
+    }
+}
+
+/// Why a run ended badly. Each kind has its own exit status.
+#[derive(Debug, PartialEq)]
+pub enum RunError {
+    Setup(String), // the rom or an output couldn't be opened
+    Vm(VmError), // the rom stopped the vm, and was still stopped when the frontend quit
+    Crashed(&'static str), // a thread panicked
+}
+
+impl RunError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunError::Setup(_) => 2,
+            RunError::Vm(_) => 3,
+            RunError::Crashed(_) => 101, // what rust exits with after a panic
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::Setup(error) => write!(f, "{}", error),
+            RunError::Vm(error) => write!(f, "the rom stopped: {}", error),
+            RunError::Crashed(thread) => write!(f, "the {} thread crashed", thread),
+        }
+    }
+}
+
+impl From<String> for RunError {
+    fn from(error: String) -> Self {
+        RunError::Setup(error)
+    }
 }
 
 pub struct Interfaces {
     pub screen: Screen,
     pub sound_timer: u8,
     pub keys: u16,
+    pub time: Duration, // emulated: the instructions run so far over the instructions a second
+    pub registers: Registers,
+    pub error: Option<VmError>, // what stopped the vm, until it's reset
+}
+
+impl Default for Interfaces {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Interfaces {
@@ -32,213 +148,1237 @@ impl Interfaces {
             screen: VM::create_screen(),
             sound_timer: 0,
             keys: 0,
+            time: Duration::ZERO,
+            registers: Registers::default(),
+            error: None,
         }
     }
 }
 
 impl Clone for Interfaces {
     fn clone(&self) -> Self {
-        Self { screen: self.screen.clone(), sound_timer: self.sound_timer.clone(), keys: self.keys.clone() }
+        Self { screen: self.screen, sound_timer: self.sound_timer, keys: self.keys, time: self.time, registers: self.registers, error: self.error }
+    }
+}
+
+/// How the vm thread executes instructions.
+#[derive(Clone, Copy)]
+pub enum Backend {
+    Interpreter,
+    Threaded,
+}
+
+/// How the vm and the frontend share the machine. On threads, the vm runs at its own pace on
+/// one while the frontend runs frames on the other, keeping in step through signals. On a single
+/// thread every frame runs its instructions, then counts down the timers, reads the input, draws
+/// and plays the audio, in that order, so runs can be reproduced exactly. Input read in a frame
+/// is what the next frame's instructions see.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Runtime {
+    #[default]
+    Threads,
+    Single,
+}
+
+impl Runtime {
+    pub fn parse(runtime: &str) -> Result<Self, String> {
+        match runtime {
+            "threads" => Ok(Self::Threads),
+            "single" => Ok(Self::Single),
+            _ => Err(format!("unknown runtime {}, expected threads or single", runtime)),
+        }
     }
 }
 
 pub struct System {
     interfaces: Arc<RwLock<Interfaces>>,
+    backend: Backend,
+    runtime: Runtime,
+    quirks: Quirks,
+    stack: StackConfig,
+    memory_map: MemoryMap,
+    ticks_per_second: u64,
+    palette: Palette,
+    anti_flicker: AntiFlicker,
+    tone: Tone,
+    osd_mode: OsdMode,
+    audio_output: Option<PathBuf>,
+    loader: RomLoader,
+    database: Option<(RomDatabase, Option<u16>)>, // and the load address the user asked for
+    recent: Option<RecentFiles>,
+    watch: Option<(PathBuf, Watch)>,
+    quit_on_error: bool,
+    console: Console,
+    screenshot_dir: PathBuf,
+    recording: Option<PathBuf>,
+    record_format: RecordFormat,
+    create_frontend: Option<Box<dyn FnOnce() -> Frontend + Send>>,
+}
+
+impl Default for System {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl System {
     pub fn new() -> Self {
         Self {
             interfaces: Arc::new(RwLock::new(Interfaces::new())),
+            backend: Backend::Interpreter,
+            runtime: Runtime::Threads,
+            quirks: Quirks::default(),
+            stack: StackConfig::default(),
+            memory_map: MemoryMap::default(),
+            ticks_per_second: DEFAULT_TICKS_PER_SECOND,
+            palette: Palette::default(),
+            anti_flicker: AntiFlicker::Off,
+            tone: Tone::default(),
+            osd_mode: OsdMode::Off,
+            audio_output: None,
+            loader: RomLoader::new(),
+            database: None,
+            recent: None,
+            watch: None,
+            quit_on_error: false,
+            console: Console { quiet: false },
+            screenshot_dir: PathBuf::from("."),
+            recording: None,
+            record_format: RecordFormat::default(),
+            create_frontend: Some(Box::new(|| io::frontend(None, io::WindowOptions::default()))),
         }
     }
 
-    pub fn init(&mut self, rom_path: String) {
-        let (vm_thread, sender) = self.start_vm_thread(rom_path);
-        let io_thread = self.start_io_thread(sender);
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    pub fn set_runtime(&mut self, runtime: Runtime) {
+        self.runtime = runtime;
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn set_stack(&mut self, stack: StackConfig) {
+        self.stack = stack;
+    }
+
+    pub fn set_memory_map(&mut self, memory_map: MemoryMap) -> Result<(), String> {
+        memory_map.validate()?;
+        self.memory_map = memory_map;
+        Ok(())
+    }
+
+    /// How many instructions the vm runs a second, 700 by default.
+    pub fn set_ticks_per_second(&mut self, ticks_per_second: u64) -> Result<(), String> {
+        if ticks_per_second == 0 {
+            return Err("the vm has to run at least one instruction a second".to_string());
+        }
+        self.ticks_per_second = ticks_per_second;
+        Ok(())
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn set_anti_flicker(&mut self, anti_flicker: AntiFlicker) {
+        self.anti_flicker = anti_flicker;
+    }
+
+    /// What the beeper sounds like, live and in recordings.
+    pub fn set_tone(&mut self, tone: Tone) {
+        self.tone = tone;
+    }
+
+    /// What the overlay shows besides notifications, to start with. A hotkey cycles through the
+    /// modes.
+    pub fn set_osd_mode(&mut self, mode: OsdMode) {
+        self.osd_mode = mode;
+    }
+
+    /// Also writes the sound to a wav file, rendered in step with emulated time. Works with any
+    /// frontend, including the null one.
+    pub fn set_audio_output(&mut self, path: PathBuf) {
+        self.audio_output = Some(path);
+    }
+
+    /// Sets how roms are read and where they're loaded.
+    pub fn set_rom_loader(&mut self, loader: RomLoader) {
+        self.loader = loader;
+    }
+
+    /// Roms dropped on the window are looked up in `database` for their quirks, speed, load
+    /// address and keys, like the first one. A `load_address` the user asked for wins over the
+    /// database's.
+    pub fn set_rom_database(&mut self, database: RomDatabase, load_address: Option<u16>) {
+        self.database = Some((database, load_address));
+    }
+
+    /// Where roms dropped on the window are remembered, along with the one started from the
+    /// launcher.
+    pub fn set_recent_files(&mut self, recent: RecentFiles) {
+        self.recent = Some(recent);
+    }
+
+    /// Reloads the rom whenever the file at `path` changes, for working on a rom while it runs.
+    pub fn set_watch(&mut self, path: PathBuf, watch: Watch) {
+        self.watch = Some((path, watch));
+    }
+
+    /// Ends the run as soon as the rom stops the vm, rather than waiting for a reset or a quit
+    /// that won't come, e.g. when headless.
+    pub fn set_quit_on_error(&mut self, quit_on_error: bool) {
+        self.quit_on_error = quit_on_error;
+    }
+
+    /// Keeps status messages, like where a screenshot was saved, off stdout and stderr, for
+    /// frontends that draw in the terminal. They still show on screen.
+    pub fn set_quiet(&mut self, quiet: bool) {
+        self.console = Console { quiet };
+    }
+
+    /// Where screenshots and recordings started by hotkey are saved, the working directory by
+    /// default.
+    pub fn set_screenshot_dir(&mut self, dir: PathBuf) {
+        self.screenshot_dir = dir;
+    }
+
+    /// Records from the first frame to `path`, a .gif or .y4m file. Works with any frontend,
+    /// including the null one.
+    pub fn set_recording(&mut self, path: PathBuf) {
+        self.recording = Some(path);
+    }
 
-        vm_thread.join().expect("vm thread panicked");
-        io_thread.join().expect("io thread panicked");
+    /// What the recording hotkey records to, gif by default.
+    pub fn set_record_format(&mut self, format: RecordFormat) {
+        self.record_format = format;
     }
 
-    fn get_file_as_byte_vec(filename: String) -> Vec<u8> {
-        let mut f = File::open(&filename).expect("no file found");
-        let metadata = fs::metadata(&filename).expect("unable to read metadata");
-        if metadata.len() > 4096 {
-            panic!("filesize too large");
+    /// Sets how the io thread builds its frontend, the sdl window by default. The frontend is
+    /// created on the io thread itself since sdl's types can't be sent between threads.
+    pub fn set_frontend<F>(&mut self, create_frontend: F)
+    where
+        F: FnOnce() -> Frontend + Send + 'static,
+    {
+        self.create_frontend = Some(Box::new(create_frontend));
+    }
+
+    /// Runs the rom at `rom_path` (`-` for stdin) until the frontend quits.
+    pub fn init(&mut self, rom_path: String) -> Result<(), RunError> {
+        let rom = self.loader.load_path(Path::new(&rom_path))?;
+        self.run(rom)
+    }
+
+    /// Same as `init`, for a rom that's already in memory.
+    pub fn init_with_bytes(&mut self, bytes: &[u8]) -> Result<(), RunError> {
+        let rom = self.loader.load_bytes(bytes)?;
+        self.run(rom)
+    }
+
+    /// Runs a rom that's already been loaded. An Octo cartridge's settings take the place of any
+    /// set on the system. A rom that stops the vm doesn't end the run, since it can be reset or
+    /// replaced, but it's the run's error if it's still stopped at the end.
+    pub fn run(&mut self, rom: Rom) -> Result<(), RunError> {
+        self.loader.validate(&rom.bytes)?;
+        if let Some(options) = rom.options {
+            self.quirks = options.quirks;
+            if let Some(ticks_per_second) = options.ticks_per_second() {
+                self.ticks_per_second = ticks_per_second;
+            }
+            if let Some(palette) = options.palette {
+                self.palette = palette;
+            }
         }
-        let mut buffer = vec![0; metadata.len() as usize];
-        f.read(&mut buffer).expect("buffer overflow");
 
-        buffer
+        let recorder = self.recording.as_ref().map(|path| Recorder::create(path, self.palette)).transpose()?;
+        let audio_output = self.audio_output.as_deref().map(WavWriter::create).transpose()?;
+
+        let (sound_sender, sound_receiver) = channel();
+        let machine = Machine::new(self, rom.bytes, sound_sender);
+        let stopped = match self.runtime {
+            Runtime::Threads => {
+                let (vm_thread, sender) = self.start_vm_thread(machine);
+                let io_loop = self.io_loop(VmLink::Thread(sender), sound_receiver, recorder, audio_output);
+                let console = self.console;
+                let io_thread = thread::spawn(move || {
+                    console.info("Starting io thread");
+                    io_loop()
+                });
+
+                // either thread going down takes the other with it: the vm stops when the io
+                // thread hangs up, and the io thread when the vm stops listening
+                let vm_result = vm_thread.join();
+                let io_result = io_thread.join();
+                vm_result.map_err(|_| RunError::Crashed("vm"))?;
+                io_result.map_err(|_| RunError::Crashed("io"))?
+            },
+            Runtime::Single => {
+                self.io_loop(VmLink::Inline(Box::new(machine)), sound_receiver, recorder, audio_output)()
+            },
+        };
+        match stopped {
+            Some(error) => Err(RunError::Vm(error)),
+            None => Ok(()),
+        }
     }
 
-    fn start_vm_thread(&mut self, rom_path: String) -> (JoinHandle<()>, Sender<Signal>) {
+    fn start_vm_thread(&mut self, mut machine: Machine) -> (JoinHandle<()>, Sender<Signal>) {
         let (sender, receiver): (Sender<Signal>, Receiver<Signal>) = channel();
         let interfaces = self.interfaces.clone();
+        let console = self.console;
         let vm_thread = thread::spawn(move || {
-            println!("Starting vm thread");
-            let ticks_per_second = 700;
-            let target_interval = Duration::from_micros(1_000_000 / ticks_per_second);
-
-            let mut vm = VM::new();
-            vm.load_rom(Self::get_file_as_byte_vec(rom_path));
-            let mut local_interfaces = Interfaces::new();
+            console.info("Starting vm thread");
+            // the threaded backend gets a frame's worth of instructions at a time. one at a time,
+            // every instruction would start a block of its own, translated to run just once
+            let batched = machine.threaded.is_some();
 
             loop {
                 let tick_start = Instant::now();
 
                 let write_start = Instant::now();
-                {
-                    Self::tick(&mut vm, &mut local_interfaces, &receiver);
-
-                    if let Status::Terminated = vm.status {
+                loop {
+                    match receiver.try_recv() {
+                        Ok(signal) => machine.handle(signal),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            // the io thread's gone without saying, so there's no one to run for
+                            machine.handle(Signal::Terminate);
+                            break;
+                        },
+                    }
+                    if machine.terminated() {
                         break;
                     }
                 }
+                if machine.terminated() {
+                    break;
+                }
+                let batch = if batched { machine.pacing.next_frame() } else { 1 };
+                let ran = machine.run(batch) > 0;
                 let write_elapsed = write_start.elapsed();
 
                 let clone_start = Instant::now();
-                {
-                    let new_interfaces = local_interfaces.clone();
-                    let mut interfaces = interfaces.write().unwrap();
-                    *interfaces = new_interfaces;
-                }
+                machine.publish(&interfaces);
                 let clone_elapsed = clone_start.elapsed();
 
+                // frame advances run flat out, and while paused there's just the signals to wait for.
+                // a dropped rom can change the rate, so it's worked out each time
+                let target_interval = if batched {
+                    Duration::from_secs(1) / FRAMES_PER_SECOND as u32
+                } else {
+                    Duration::from_secs(1) / machine.ticks_per_second as u32
+                };
+                let pacing = &machine.pacing;
+                let target_interval = if pacing.frame_budget > 0 {
+                    Duration::ZERO
+                } else if pacing.speed == 0.0 {
+                    Duration::from_millis(1)
+                } else {
+                    target_interval.div_f64(pacing.speed)
+                };
                 let elapsed = tick_start.elapsed();
                 if elapsed < target_interval {
                     thread::sleep(target_interval - elapsed);
-                } else if elapsed > target_interval * 10 {
-                    println!(
+                } else if ran && elapsed > target_interval * 10 && target_interval > Duration::ZERO {
+                    console.info(format!(
                         "ticker ticked for far too long! {}μs, should be {}μs ({}μs ticking, {}μs cloning)",
                         elapsed.as_micros(),
                         target_interval.as_micros(),
                         write_elapsed.as_micros(),
                         clone_elapsed.as_micros(),
-                    );
+                    ));
                 }
-
-
             }
         });
         (vm_thread, sender)
     }
 
-    fn tick(vm: &mut VM, local_interfaces: &mut Interfaces, receiver: &Receiver<Signal>) {
-        'delay: loop {
-            match receiver.try_recv() {
-                Ok(signal) => match signal {
-                    Signal::DecrementDelayTimer => if vm.delay_timer > 0 { vm.delay_timer -= 1 }
-                    Signal::DecrementSoundTimer => if local_interfaces.sound_timer > 0 { local_interfaces.sound_timer -= 1 }
-                    Signal::Terminate => vm.terminate(),
-                    Signal::SendKeys(keys) => local_interfaces.keys = keys,
-                },
-                Err(TryRecvError::Empty) => {
-                    break 'delay;
-                },
-                Err(TryRecvError::Disconnected) => {
-                    panic!("The signal channel has been disconnected");
-                },
-            }
-        }
+    // the frontend's loop, drawing, playing and reading input a frame at a time and driving the vm
+    // through `link`. returns whatever stopped the vm, if it was still stopped at the end
+    fn io_loop(
+        &mut self,
+        mut link: VmLink,
+        sound: Receiver<SoundEvent>,
+        mut recorder: Option<Recorder>,
+        mut audio_output: Option<WavWriter>,
+    ) -> impl FnOnce() -> Option<VmError> + Send + 'static {
+        let interfaces = self.interfaces.clone();
+        let create_frontend = self.create_frontend.take().expect("system can only be started once");
+        let palette = self.palette;
+        let anti_flicker = self.anti_flicker;
+        let screenshot_dir = self.screenshot_dir.clone();
+        let record_format = self.record_format;
+        let tone = self.tone;
+        let osd_mode = self.osd_mode;
+        let mut instructions_per_second = self.ticks_per_second as f64;
+        let mut loader = self.loader;
+        let mut setup = Setup {
+            quirks: self.quirks,
+            ticks_per_second: self.ticks_per_second,
+            load_address: self.loader.load_address(),
+        };
+        // without a database, the loader's address is the user's
+        let (database, load_address) = match self.database.take() {
+            Some((database, load_address)) => (Some(database), load_address),
+            None => (None, Some(self.loader.load_address())),
+        };
+        let mut recent = self.recent.take();
+        let watch = self.watch.clone();
+        let quit_on_error = self.quit_on_error;
+        let console = self.console;
+        move || {
+            let target_interval = Duration::from_secs(1) / FRAMES_PER_SECOND as u32;
 
-        vm.tick(local_interfaces);
-    }
+            let mut frontend = create_frontend();
+            frontend.display.set_palette(palette);
+            frontend.display.set_anti_flicker(anti_flicker);
+            frontend.audio.set_tone(tone);
+            let mut renderer = AudioRenderer::new(tone);
+            let mut muted = false;
 
-    fn start_io_thread(&mut self, sender: Sender<Signal>) -> JoinHandle<()> {
-        let interfaces = self.interfaces.clone();
-        let io_thread = thread::spawn(move || {
-            println!("Starting io thread");
+            // the theme hotkey steps from the starting palette through the built in themes
+            let mut themes = vec![palette];
+            for (name, _) in THEMES {
+                let theme = Palette::theme(name).unwrap();
+                if theme != palette {
+                    themes.push(theme);
+                }
+            }
+            let mut theme = 0;
 
-            let ticks_per_second = 60;
-            let target_interval = Duration::from_micros(1_000_000 / ticks_per_second);
+            let mut paused = false;
+            let mut fast_forward = false;
+            let mut slow_motion = false;
+            let mut speed = 1.0;
+            let mut title = String::new();
+            let mut stopped = None; // by the rom, and why
 
-            let mut io = IO::new();
+            let mut osd = Osd::new(osd_mode);
+            // frames drawn and emulated time since the rates were last worked out
+            let mut stats_start = (Instant::now(), 0, Duration::ZERO);
+            let mut rates = (0.0, 0.0); // frames and instructions a second
 
-            let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-            let sink = Sink::try_new(&stream_handle).unwrap();
-            let mut beeping = false;
+            // the watched rom, its options and when it last changed
+            let mut watching = watch.map(|(path, watch)| {
+                let modified = file_modified(&path);
+                (path, watch, modified)
+            });
+            let mut frame: u64 = 0;
 
-            'main: loop {
+            loop {
                 let tick_start = Instant::now();
 
-                let input = Self::process_input(&io);
-                sender.send(Signal::SendKeys(input)).unwrap();
-
-                if io.event_pump.keyboard_state().is_scancode_pressed(Scancode::Escape) {
-                    sender.send(Signal::Terminate).unwrap();
-                    break 'main;
+                link.run_frame(&interfaces);
+                let input = frontend.input.poll();
+                if input.quit {
+                    link.send(Signal::Terminate);
+                    break;
+                }
+                // the vm thread only stops listening if it's crashed. the other signals this frame
+                // can go unheard, it's found out here on the next
+                if !link.send(Signal::SendKeys(input.keys)) {
+                    break;
                 }
 
-                'event: loop {
-                    match io.event_pump.poll_event() {
-                        Some(event) => match event {
-                            Event::Quit { timestamp: _ } => {
-                                sender.send(Signal::Terminate).unwrap();
-                                break 'main;
+                if input.reset {
+                    link.send(Signal::Reset);
+                    osd.notify("Reset");
+                }
+                if let Some(path) = frontend.input.take_dropped() {
+                    let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+                    match load_dropped(&path, database.as_ref(), load_address) {
+                        Ok((rom, dropped, dropped_loader, actions)) => {
+                            link.send(Signal::Load(rom.bytes, dropped, false));
+                            setup = dropped;
+                            loader = dropped_loader;
+                            instructions_per_second = setup.ticks_per_second as f64;
+                            frontend.input.set_rom(&name, &actions);
+                            osd.notify(format!("Loaded {}", name));
+                            // and the dropped rom is the one to watch from now on
+                            if let Some((watched, _, modified)) = &mut watching {
+                                *modified = file_modified(&path);
+                                *watched = path.clone();
+                            }
+                            if let Some(recent) = &mut recent {
+                                if let Err(error) = recent.add(&path) {
+                                    console.error(error);
+                                }
                             }
-                            _ => {},
+                        },
+                        Err(error) => {
+                            console.error(error);
+                            osd.notify(format!("Unable to load {}", name));
+                        },
+                    }
+                }
+                if input.cycle_osd {
+                    osd.mode = osd.mode.next();
+                }
+                paused ^= input.toggle_pause;
+                fast_forward ^= input.toggle_fast_forward;
+                slow_motion ^= input.toggle_slow_motion;
+                let new_speed = if paused {
+                    0.0
+                } else if fast_forward || input.fast_forward {
+                    FAST_FORWARD
+                } else if slow_motion {
+                    SLOW_MOTION
+                } else {
+                    1.0
+                };
+                if new_speed != speed {
+                    speed = new_speed;
+                    link.send(Signal::SetSpeed(speed));
+                    osd.notify(if speed == 0.0 { "Paused".to_string() } else { format!("Speed {}%", (speed * 100.0) as u32) });
+                }
+                // the vm only moves on while paused if it's asked to advance a frame
+                let advancing = speed > 0.0 || input.advance_frame;
+
+                frame += 1;
+                if let Some((path, watch, modified)) = watching.as_mut().filter(|_| frame.is_multiple_of(WATCH_INTERVAL)) {
+                    let changed = file_modified(path);
+                    if changed != *modified {
+                        *modified = changed;
+                        // a half written file fails to load, and is tried again once it's finished
+                        match load_rom(&loader, path) {
+                            Ok(rom) => {
+                                link.send(Signal::Load(rom.bytes, setup, watch.replay));
+                                console.info(format!("Reloaded {}", path.display()));
+                                osd.notify("Reloaded");
+                            },
+                            Err(error) => {
+                                console.error(error);
+                                osd.notify("Reload failed");
+                            },
                         }
-                        None => break 'event,
                     }
                 }
 
-                {
+                if input.next_theme {
+                    theme = (theme + 1) % themes.len();
+                    frontend.display.set_palette(themes[theme]);
+                    osd.notify(format!("Theme {}", theme + 1));
+                }
+                if input.toggle_fullscreen {
+                    frontend.display.toggle_fullscreen();
+                }
+                if input.toggle_mute {
+                    muted = !muted;
+                    frontend.audio.set_muted(muted);
+                    osd.notify(if muted { "Muted" } else { "Sound on" });
+                }
+                if input.toggle_recording {
+                    match recorder.take() {
+                        Some(recorder) => {
+                            finish_recording(recorder, console);
+                            osd.notify("Recording saved");
+                        },
+                        None => {
+                            let path = screenshot::timestamped_path(&screenshot_dir, record_format.extension());
+                            match Recorder::create(&path, themes[theme]) {
+                                Ok(started) => {
+                                    console.info(format!("Recording to {}", path.display()));
+                                    osd.notify("Recording");
+                                    recorder = Some(started);
+                                },
+                                Err(error) => {
+                                    console.error(format!("unable to start recording: {}", error));
+                                    osd.notify("Unable to record");
+                                },
+                            }
+                        },
+                    }
+                }
+
+                let (screen, sound_timer, time, registers, error) = {
                     let interfaces = interfaces.read().unwrap();
-                    io.draw_screen(&interfaces.screen);
-                }
-
-                // ask the ticker thread to decrement delay timers
-                sender.send(Signal::DecrementDelayTimer).unwrap();
-                sender.send(Signal::DecrementSoundTimer).unwrap();
-
-                {
-                    let sound_timer = { interfaces.read().unwrap().sound_timer };
-                    if sound_timer > 0 && !beeping {
-                        let source = SineWave::new(500.0).take_duration(Duration::from_secs(5)).amplify(0.2);
-                        sink.append(source);
-                        beeping = true;
-                    } else if sound_timer == 0 && beeping {
-                        sink.clear();
-                        beeping = false;
+                    (interfaces.screen, interfaces.sound_timer, interfaces.time, interfaces.registers, interfaces.error)
+                };
+                // a rom that stops the vm leaves what it was showing up, with the reason over it
+                if error != stopped {
+                    if let Some(error) = error {
+                        console.error(error);
+                    }
+                    osd.set_error(error.map(|error| error.to_string()));
+                    stopped = error;
+                }
+
+                let new_title = if stopped.is_some() {
+                    "chip-8-rs - stopped".to_string()
+                } else if speed == 0.0 {
+                    "chip-8-rs - paused".to_string()
+                } else if speed == 1.0 {
+                    "chip-8-rs".to_string()
+                } else {
+                    format!("chip-8-rs - {}%", (speed * 100.0) as u32)
+                };
+                if new_title != title {
+                    frontend.display.set_title(&new_title);
+                    title = new_title;
+                }
+
+                for event in sound.try_iter() {
+                    renderer.push(event);
+                }
+                let samples = renderer.render(time);
+                let shown = input.screen.unwrap_or(screen);
+
+                // averaged over half a second so the numbers can be read
+                let (stats_since, frames, stats_time) = &mut stats_start;
+                *frames += 1;
+                let elapsed = stats_since.elapsed().as_secs_f64();
+                if elapsed >= 0.5 {
+                    let ran = time.saturating_sub(*stats_time).as_secs_f64() * instructions_per_second;
+                    rates = (*frames as f64 / elapsed, ran / elapsed);
+                    stats_start = (Instant::now(), 0, time);
+                }
+                osd.set_stats(rates.0, rates.1, &registers, sound_timer);
+                frontend.display.set_osd(&osd.lines());
+                frontend.display.draw(&shown);
+
+                if let Some(started) = recorder.as_mut().filter(|_| advancing) {
+                    if let Err(error) = started.frame(&shown, &themes[theme], &samples) {
+                        console.error(format!("recording stopped: {}", error));
+                        osd.notify("Recording stopped");
+                        recorder = None;
                     }
                 }
 
+                if let Some(mode) = input.screenshot {
+                    let image = frontend.display.capture(mode)
+                        .unwrap_or_else(|| screenshot::native(&shown, &themes[theme]));
+                    match screenshot::save(&screenshot_dir, &image) {
+                        Ok(path) => {
+                            console.info(format!("Saved screenshot to {}", path.display()));
+                            osd.notify("Screenshot saved");
+                        },
+                        Err(error) => {
+                            console.error(format!("unable to save screenshot: {}", error));
+                            osd.notify("Unable to save screenshot");
+                        },
+                    }
+                }
+
+                // ask the ticker thread to decrement delay timers. these go before the frame advance
+                // so the frame starts with a vblank. on one thread the frame's already done it
+                if advancing && matches!(link, VmLink::Thread(_)) {
+                    link.send(Signal::DecrementDelayTimer);
+                    link.send(Signal::DecrementSoundTimer);
+                }
+                if speed == 0.0 && input.advance_frame {
+                    link.send(Signal::AdvanceFrame);
+                }
+
+                frontend.audio.set_beeping(sound_timer > 0);
+                // sped up or slowed down there's more or less audio than real time, so it isn't played
+                if speed == 1.0 {
+                    frontend.audio.play(&samples);
+                }
+                if let Some(wav) = &mut audio_output {
+                    if let Err(error) = wav.write(&samples) {
+                        console.error(format!("audio output stopped: {}", error));
+                        osd.notify("Audio output stopped");
+                        audio_output = None;
+                    }
+                }
+
+                // with no one to reset it, a stopped rom is the end of the run
+                if quit_on_error && stopped.is_some() {
+                    link.send(Signal::Terminate);
+                    break;
+                }
+
+                // frames come faster or slower with the speed, so the timers keep pace with the vm
+                let target_interval = if speed > 0.0 { target_interval.div_f64(speed) } else { target_interval };
                 let elapsed = tick_start.elapsed();
                 if elapsed < target_interval {
                     thread::sleep(target_interval - elapsed);
                 }
             }
-        });
 
-        io_thread
-    }
-
-    fn process_input(io: &IO) -> u16 {
-        let keeb = io.event_pump.keyboard_state();
-        let mut input: u16 = 0;
-        // 1 2 3 C
-        // 4 5 6 D
-        // 7 8 9 E
-        // A 0 B F
-        if keeb.is_scancode_pressed(Scancode::Num1) { input += 1 << 1; } // 1
-        if keeb.is_scancode_pressed(Scancode::Num2) { input += 1 << 2; } // 2
-        if keeb.is_scancode_pressed(Scancode::Num3) { input += 1 << 3; } // 3
-        if keeb.is_scancode_pressed(Scancode::Num4) { input += 1 << 12; } // C
-        if keeb.is_scancode_pressed(Scancode::Q) { input += 1 << 4; } // 4
-        if keeb.is_scancode_pressed(Scancode::W) { input += 1 << 5; } // 5
-        if keeb.is_scancode_pressed(Scancode::E) { input += 1 << 6; } // 6
-        if keeb.is_scancode_pressed(Scancode::R) { input += 1 << 13; } // D
-        if keeb.is_scancode_pressed(Scancode::A) { input += 1 << 7; } // 7
-        if keeb.is_scancode_pressed(Scancode::S) { input += 1 << 8; } // 8
-        if keeb.is_scancode_pressed(Scancode::D) { input += 1 << 9; } // 9
-        if keeb.is_scancode_pressed(Scancode::F) { input += 1 << 14; } // E
-        if keeb.is_scancode_pressed(Scancode::Z) { input += 1 << 10; } // A
-        if keeb.is_scancode_pressed(Scancode::X) { input += 1 } // 0
-        if keeb.is_scancode_pressed(Scancode::C) { input += 1 << 11; } // B
-        if keeb.is_scancode_pressed(Scancode::V) { input += 1 << 15; } // F
-
-        input
-    }
-}
\ No newline at end of file
+            // however the loop ended, crashes included, what's been recorded so far is kept
+            if let Some(recorder) = recorder.take() {
+                finish_recording(recorder, console);
+            }
+            if let Some(wav) = audio_output.take() {
+                match wav.finish() {
+                    Ok(path) => console.info(format!("Saved audio to {}", path.display())),
+                    Err(error) => console.error(format!("unable to finish audio: {}", error)),
+                }
+            }
+
+            stopped
+        }
+    }
+}
+
+/// The vm and everything that goes with running it: the rom to reset to, the backend, emulated
+/// time and how the io loop's signals have asked it to run. Driven either by the vm thread or
+/// directly by the io loop when everything's on one thread.
+struct Machine {
+    vm: VM,
+    rom: Vec<u8>,
+    quirks: Quirks,
+    stack: StackConfig,
+    memory_map: MemoryMap,
+    load_address: u16,
+    seed: Option<u64>, // for every vm, if reloads are to keep it
+    // the keys each frame ran with and how many instructions it ran, since the rom was loaded, if
+    // reloads are to replay them. recorded at the vblank, so it's what the vm saw
+    history: Option<Vec<(u16, u64)>>,
+    frame_start: u64, // ticks at the last vblank
+    threaded: Option<ThreadedBackend>,
+    interfaces: Interfaces,
+    ticks_per_second: u64,
+    ticks: u64,
+    beeping: bool,
+    pacing: Pacing,
+    sound: Sender<SoundEvent>,
+}
+
+impl Machine {
+    fn new(system: &System, rom: Vec<u8>, sound: Sender<SoundEvent>) -> Self {
+        let seed = system.watch.as_ref().filter(|(_, watch)| watch.keep_seed).map(|_| rand::random::<u64>());
+        let history = system.watch.as_ref().filter(|(_, watch)| watch.replay).map(|_| vec![]);
+        let mut machine = Self {
+            vm: VM::new(),
+            rom,
+            quirks: system.quirks,
+            stack: system.stack,
+            memory_map: system.memory_map.clone(),
+            load_address: system.loader.load_address(),
+            seed,
+            history,
+            frame_start: 0,
+            threaded: match system.backend {
+                Backend::Interpreter => None,
+                Backend::Threaded => Some(ThreadedBackend::new()),
+            },
+            interfaces: Interfaces::new(),
+            ticks_per_second: system.ticks_per_second,
+            ticks: 0,
+            beeping: false,
+            pacing: Pacing {
+                speed: 1.0,
+                frame_budget: 0,
+                instructions_per_second: system.ticks_per_second,
+                owed: 0,
+            },
+            sound,
+        };
+        machine.vm = machine.build_vm();
+        machine
+    }
+
+    fn build_vm(&self) -> VM {
+        let mut vm = VM::new();
+        vm.quirks = self.quirks;
+        vm.set_stack_config(self.stack);
+        if let Some(seed) = self.seed {
+            vm.seed(seed);
+        }
+        vm.set_memory_map(self.memory_map.clone()).expect("the memory map was checked when it was set");
+        vm.load_rom_at(self.load_address, self.rom.clone());
+        vm
+    }
+
+    fn terminated(&self) -> bool {
+        matches!(self.vm.status, Status::Terminated)
+    }
+
+    fn handle(&mut self, signal: Signal) {
+        match signal {
+            Signal::DecrementDelayTimer => {
+                // sent once a frame, so this is also the vblank
+                self.vm.vblank();
+                self.vm.delay_timer = self.vm.delay_timer.saturating_sub(1);
+                if let Some(history) = &mut self.history {
+                    history.push((self.interfaces.keys, self.ticks - self.frame_start));
+                }
+                self.frame_start = self.ticks;
+            },
+            Signal::DecrementSoundTimer => self.interfaces.sound_timer = self.interfaces.sound_timer.saturating_sub(1),
+            // the io loop hangs up straight after sending this
+            Signal::Terminate => self.vm.terminate(),
+            Signal::SendKeys(keys) => self.interfaces.keys = keys,
+            Signal::SetSpeed(speed) => {
+                self.pacing.speed = speed;
+                self.pacing.frame_budget = 0;
+            },
+            Signal::AdvanceFrame => self.pacing.frame_budget = self.pacing.next_frame(),
+            Signal::Reset => self.reset(false),
+            Signal::Load(rom, setup, replay) => {
+                self.rom = rom;
+                self.quirks = setup.quirks;
+                self.load_address = setup.load_address;
+                if setup.ticks_per_second != self.ticks_per_second {
+                    // emulated time carries on at the new rate, without stepping back
+                    self.ticks = (self.interfaces.time.as_secs_f64() * setup.ticks_per_second as f64).ceil() as u64;
+                    self.ticks_per_second = setup.ticks_per_second;
+                    self.pacing.instructions_per_second = setup.ticks_per_second;
+                    self.pacing.owed = 0;
+                }
+                self.reset(replay);
+            },
+        }
+    }
+
+    // a fresh vm with the rom reloaded, optionally caught up by replaying the frames the last one
+    // ran. emulated time carries on so the audio doesn't jump backwards
+    fn reset(&mut self, replay: bool) {
+        self.vm = self.build_vm();
+        self.threaded = self.threaded.take().map(|_| ThreadedBackend::new());
+        self.interfaces.screen = VM::create_screen();
+        self.interfaces.sound_timer = 0;
+        self.interfaces.error = None;
+        self.frame_start = self.ticks;
+
+        let frames = match &mut self.history {
+            Some(history) if replay => std::mem::take(history),
+            Some(history) => {
+                history.clear();
+                vec![]
+            },
+            None => vec![],
+        };
+        // as fast as it'll go, outside of emulated time so the audio doesn't have to catch up too.
+        // each frame runs as many instructions with the same keys as it did the first time
+        let held = self.interfaces.keys;
+        for (frame, &(keys, instructions)) in frames.iter().enumerate() {
+            self.interfaces.keys = keys;
+            if let Err(error) = self.step(instructions) {
+                self.interfaces.error = Some(error);
+                self.history = self.history.take().map(|_| frames[..frame].to_vec());
+                self.interfaces.keys = held;
+                return;
+            }
+            self.vm.vblank();
+            self.vm.delay_timer = self.vm.delay_timer.saturating_sub(1);
+            self.interfaces.sound_timer = self.interfaces.sound_timer.saturating_sub(1);
+        }
+        self.interfaces.keys = held;
+        if let Some(history) = &mut self.history {
+            *history = frames;
+        }
+    }
+
+    // runs up to `budget` instructions, unless paused or stopped, and returns how many ran. the
+    // threaded backend takes them as one batch, which is what it's quick at, so its time and
+    // sound are only brought up to date at the end of it
+    fn run(&mut self, budget: u64) -> u64 {
+        let mut ran = 0;
+        while ran < budget {
+            // stopped by the rom until a reset
+            if self.terminated() || self.interfaces.error.is_some() {
+                break;
+            }
+            let mut batch = if self.threaded.is_some() { budget - ran } else { 1 };
+            if self.pacing.speed == 0.0 {
+                batch = batch.min(self.pacing.frame_budget);
+                if batch == 0 {
+                    break;
+                }
+                self.pacing.frame_budget -= batch;
+            }
+
+            if let Err(error) = self.step(batch) {
+                self.interfaces.error = Some(error);
+                break;
+            }
+            ran += batch;
+            self.interfaces.registers = self.vm.registers();
+
+            // the beeper changing is stamped with when it happened in emulated time, which the io
+            // loop only renders audio up to once it's seen the interfaces reach it
+            self.ticks += batch;
+            self.interfaces.time = Duration::from_secs_f64(self.ticks as f64 / self.ticks_per_second as f64);
+            if self.beeping != (self.interfaces.sound_timer > 0) {
+                self.beeping = !self.beeping;
+                // the io loop may have already gone, in which case no one's listening
+                let _ = self.sound.send(SoundEvent { time: self.interfaces.time, on: self.beeping });
+            }
+        }
+        ran
+    }
+
+    fn step(&mut self, instructions: u64) -> Result<(), VmError> {
+        match &mut self.threaded {
+            Some(backend) => backend.run(&mut self.vm, &mut self.interfaces, instructions as usize).map(|_| ()),
+            None => (0..instructions).try_for_each(|_| self.vm.tick(&mut self.interfaces)),
+        }
+    }
+
+    // makes the latest state visible to the io loop
+    fn publish(&self, shared: &RwLock<Interfaces>) {
+        let new_interfaces = self.interfaces.clone();
+        let mut interfaces = shared.write().unwrap();
+        *interfaces = new_interfaces;
+    }
+}
+
+// how the io loop reaches the vm: over a channel to the vm thread, or in place, running a
+// frame's worth of instructions and counting down the timers at the start of every frame
+enum VmLink {
+    Thread(Sender<Signal>),
+    Inline(Box<Machine>),
+}
+
+impl VmLink {
+    // false once the vm thread's gone
+    fn send(&mut self, signal: Signal) -> bool {
+        match self {
+            VmLink::Thread(sender) => sender.send(signal).is_ok(),
+            VmLink::Inline(machine) => {
+                machine.handle(signal);
+                true
+            },
+        }
+    }
+
+    fn run_frame(&mut self, interfaces: &RwLock<Interfaces>) {
+        if let VmLink::Inline(machine) = self {
+            // the timers only count down in frames that run, at speed or advanced while paused
+            let advancing = machine.pacing.speed > 0.0 || machine.pacing.frame_budget > 0;
+            let instructions = machine.pacing.next_frame();
+            machine.run(instructions);
+            if advancing {
+                machine.handle(Signal::DecrementDelayTimer);
+                machine.handle(Signal::DecrementSoundTimer);
+            }
+            machine.publish(interfaces);
+        }
+    }
+}
+
+// reads a rom from a file while running, as the system's loader would at the start
+fn load_rom(loader: &RomLoader, path: &Path) -> Result<Rom, String> {
+    let rom = loader.load_path(path)?;
+    loader.validate(&rom.bytes)?;
+    Ok(rom)
+}
+
+// a dropped rom, with what it runs with and the loader for reloading it: its settings from the
+// database or its cartridge, over the defaults. it doesn't keep the first rom's
+fn load_dropped(path: &Path, database: Option<&RomDatabase>, load_address: Option<u16>) -> Result<(Rom, Setup, RomLoader, ActionKeys), String> {
+    let mut loader = RomLoader::new();
+    loader.set_load_address(load_address.unwrap_or(DEFAULT_LOAD_ADDRESS))?;
+    let rom = loader.load_path(path)?;
+
+    let mut setup = Setup {
+        quirks: Quirks::default(),
+        ticks_per_second: DEFAULT_TICKS_PER_SECOND,
+        load_address: loader.load_address(),
+    };
+    let mut actions = ActionKeys::new();
+    if let Some(info) = database.and_then(|database| database.lookup(&rom.bytes)) {
+        setup.quirks = info.quirks;
+        setup.ticks_per_second = info.ticks_per_second().unwrap_or(setup.ticks_per_second);
+        if let (Some(address), None) = (info.start_address, load_address) {
+            setup.load_address = address;
+        }
+        actions = info.keys;
+    }
+    if let Some(options) = &rom.options {
+        setup.quirks = options.quirks;
+        setup.ticks_per_second = options.ticks_per_second().unwrap_or(setup.ticks_per_second);
+    }
+
+    loader.set_load_address(setup.load_address)?;
+    loader.validate(&rom.bytes)?;
+    Ok((rom, setup, loader, actions))
+}
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn finish_recording(recorder: Recorder, console: Console) {
+    match recorder.finish() {
+        Ok(path) => console.info(format!("Saved recording to {}", path.display())),
+        Err(error) => console.error(format!("unable to finish recording: {}", error)),
+    }
+}
+
+// where status messages go, unless the frontend's drawing over the terminal
+#[derive(Clone, Copy)]
+struct Console {
+    quiet: bool,
+}
+
+impl Console {
+    fn info(&self, message: impl fmt::Display) {
+        if !self.quiet {
+            println!("{}", message);
+        }
+    }
+
+    fn error(&self, message: impl fmt::Display) {
+        if !self.quiet {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{ Arc, Mutex, RwLock };
+    use std::time::{ Duration, SystemTime };
+
+    use super::{ Backend, Interfaces, RunError, Runtime, Setup, System, Watch, WATCH_INTERVAL, load_dropped };
+    use crate::romdb::RomDatabase;
+    use crate::vm::{ Quirks, Registers, VmError };
+    use crate::frontend::{ Frontend, Input, InputBackend, RecordingDisplay, RecordingAudio, ScriptedInput };
+
+    #[test]
+    fn drives_frontend() {
+        let rom_path = env::temp_dir().join(format!("chip-8-rs-drives-frontend-{}.ch8", std::process::id()));
+        fs::write(&rom_path, [
+            0x60, 0x05, // 0x200: V0 = 5
+            0xF0, 0x29, // 0x202: I = font character
+            0xD0, 0x15, // 0x204: draw it at (V0, V1)
+            0x62, 0x3C, // 0x206: V2 = 60
+            0xF2, 0x18, // 0x208: sound timer = V2
+            0x12, 0x0A, // 0x20A: jump 0x20A
+        ]).unwrap();
+
+        let display = RecordingDisplay::default();
+        let audio = RecordingAudio::default();
+        let mut sys = System::new();
+        {
+            let display = display.clone();
+            let audio = audio.clone();
+            sys.set_frontend(move || Frontend {
+                display: Box::new(display),
+                input: Box::new(ScriptedInput::new(vec![0; 10])),
+                audio: Box::new(audio),
+            });
+        }
+        sys.init(rom_path.to_str().unwrap().to_string()).unwrap();
+        fs::remove_file(&rom_path).unwrap();
+
+        let frames = display.frames.lock().unwrap();
+        assert_eq!(frames.len(), 10);
+        assert!(frames.last().unwrap().iter().flatten().any(|pixel| *pixel));
+        assert!(audio.frames.lock().unwrap().iter().any(|beeping| *beeping));
+    }
+
+    #[test]
+    fn reports_vm_errors() {
+        let mut sys = System::new();
+        sys.set_frontend(|| Frontend { input: Box::new(ScriptedInput::new(vec![0; 5])), ..Frontend::null() });
+        let result = sys.init_with_bytes(&[0x60, 0x01, 0xF1, 0xFF]);
+        assert_eq!(result, Err(RunError::Vm(VmError::UnknownOpcode { opcode: 0xF1FF, address: 0x202 })));
+        assert_eq!(result.unwrap_err().exit_code(), 3);
+    }
+
+    #[test]
+    fn quits_on_error_with_outputs_saved() {
+        let wav_path = env::temp_dir().join(format!("chip-8-rs-quits-on-error-{}.wav", std::process::id()));
+        let mut sys = System::new();
+        sys.set_quit_on_error(true);
+        sys.set_audio_output(wav_path.clone());
+        // the null frontend never quits, so this only ends because of the error
+        sys.set_frontend(Frontend::null);
+        let result = sys.init_with_bytes(&[0x60, 0x3C, 0xF0, 0x18, 0xF1, 0xFF]);
+        assert_eq!(result, Err(RunError::Vm(VmError::UnknownOpcode { opcode: 0xF1FF, address: 0x204 })));
+
+        let wav = fs::read(&wav_path).unwrap();
+        fs::remove_file(&wav_path).unwrap();
+        let data_size = u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]);
+        assert!(data_size > 0);
+        assert_eq!(data_size as usize, wav.len() - 44);
+    }
+
+    #[test]
+    fn single_thread_runs_whole_frames() {
+        for backend in [Backend::Interpreter, Backend::Threaded] {
+            let mut sys = System::new();
+            sys.set_runtime(Runtime::Single);
+            sys.set_backend(backend);
+            sys.set_frontend(|| Frontend { input: Box::new(ScriptedInput::new(vec![0; 10])), ..Frontend::null() });
+            // V0 counts every other instruction, the rest being the jump back
+            sys.init_with_bytes(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+
+            // 11 and two thirds instructions a frame at 700 a second, for the 10 frames drawn and
+            // the one that quit
+            let interfaces = sys.interfaces.read().unwrap();
+            assert_eq!(interfaces.registers.v[0], 64);
+            assert_eq!(interfaces.registers.pc, 0x200);
+            assert_eq!(interfaces.time, Duration::from_secs_f64(128.0 / 700.0));
+        }
+    }
+
+    #[test]
+    fn dropped_roms_use_the_database() {
+        let dir = env::temp_dir().join(format!("chip-8-rs-dropped-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom = [0x12, 0x00];
+        let rom_path = dir.join("spin.ch8");
+        fs::write(&rom_path, rom).unwrap();
+        let hash = sha1_smol::Sha1::from(rom).digest().to_string();
+        fs::write(dir.join("sha1-hashes.json"), format!(r#"{{ "{}": 0 }}"#, hash)).unwrap();
+        fs::write(dir.join("programs.json"), format!(r#"[{{
+            "title": "Spin",
+            "roms": {{ "{}": {{ "platforms": ["originalChip8"], "startAddress": 1536, "keys": {{ "a": 6 }} }} }}
+        }}]"#, hash)).unwrap();
+        fs::write(dir.join("platforms.json"), r#"[{ "id": "originalChip8", "defaultTickrate": 15, "quirks": { "shift": true } }]"#).unwrap();
+        let database = RomDatabase::load(&dir).unwrap();
+
+        let (_, setup, loader, actions) = load_dropped(&rom_path, Some(&database), None).unwrap();
+        assert!(setup.quirks.shift);
+        assert_eq!(setup.ticks_per_second, 900);
+        assert_eq!(setup.load_address, 0x600);
+        assert_eq!(loader.load_address(), 0x600);
+        assert_eq!(actions.get("a"), Some(&6));
+
+        // the user's load address wins, and roms the database doesn't know get the defaults
+        let (_, setup, _, _) = load_dropped(&rom_path, Some(&database), Some(0x300)).unwrap();
+        assert_eq!(setup.load_address, 0x300);
+        let (_, setup, _, actions) = load_dropped(&rom_path, None, None).unwrap();
+        assert_eq!(setup, Setup { quirks: Quirks::default(), ticks_per_second: 700, load_address: 0x200 });
+        assert!(actions.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // plays back a frame of keys per poll like `ScriptedInput`, and on `rewrite_at` notes the
+    // vm's registers and rewrites the watched rom
+    struct RewritingInput {
+        keys: Vec<u16>,
+        polls: usize,
+        rewrite_at: usize,
+        path: PathBuf,
+        rom: Vec<u8>,
+        interfaces: Arc<RwLock<Interfaces>>,
+        before: Arc<Mutex<Option<Registers>>>,
+    }
+
+    impl InputBackend for RewritingInput {
+        fn poll(&mut self) -> Input {
+            self.polls += 1;
+            if self.polls == self.rewrite_at {
+                *self.before.lock().unwrap() = Some(self.interfaces.read().unwrap().registers);
+                fs::write(&self.path, &self.rom).unwrap();
+                // a later modified time than the first write, however coarse the filesystem's clock
+                let file = fs::File::options().write(true).open(&self.path).unwrap();
+                file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+            }
+            match self.keys.get(self.polls - 1) {
+                Some(keys) => Input { keys: *keys, ..Input::default() },
+                None => Input { quit: true, ..Input::default() },
+            }
+        }
+    }
+
+    // runs `rom` on one thread with `keys`, rewriting it to `reloaded` at the first check for
+    // changes. returns the registers just before the rewrite and at the end
+    fn run_rewritten(name: &str, rom: &[u8], reloaded: Vec<u8>, keys: Vec<u16>) -> (Registers, Registers) {
+        let rom_path = env::temp_dir().join(format!("chip-8-rs-{}-{}.ch8", name, std::process::id()));
+        fs::write(&rom_path, rom).unwrap();
+
+        let mut sys = System::new();
+        sys.set_runtime(Runtime::Single);
+        sys.set_watch(rom_path.clone(), Watch { keep_seed: true, replay: true });
+        let before = Arc::new(Mutex::new(None));
+        {
+            let input = RewritingInput {
+                keys,
+                polls: 0,
+                rewrite_at: WATCH_INTERVAL as usize,
+                path: rom_path.clone(),
+                rom: reloaded,
+                interfaces: sys.interfaces.clone(),
+                before: before.clone(),
+            };
+            sys.set_frontend(move || Frontend { input: Box::new(input), ..Frontend::null() });
+        }
+        sys.init(rom_path.to_str().unwrap().to_string()).unwrap();
+        fs::remove_file(&rom_path).unwrap();
+
+        let before = before.lock().unwrap().unwrap();
+        let after = sys.interfaces.read().unwrap().registers;
+        (before, after)
+    }
+
+    #[test]
+    fn watch_reloads_with_seed_and_replay() {
+        let rom = [
+            0xC0, 0xFF, // 0x200: V0 = random
+            0x12, 0x02, // 0x202: jump 0x202
+        ];
+        let reloaded = vec![
+            0xC0, 0xFF, // 0x200: V0 = random, the same again with the seed kept
+            0xE1, 0x9E, // 0x202: skip the next if key V1 (0) is held
+            0x12, 0x02, // 0x204: jump 0x202
+            0x62, 0x01, // 0x206: V2 = 1
+            0x12, 0x08, // 0x208: jump 0x208
+        ];
+        // key 0 is only held before the rewrite, so only the replay can see it
+        let (before, after) = run_rewritten("watch", &rom, reloaded, [vec![1; 5], vec![0; 25]].concat());
+        assert_eq!(after.v[0], before.v[0]);
+        assert_eq!(after.v[2], 1);
+        assert_eq!(after.pc, 0x208);
+    }
+
+    #[test]
+    fn replay_lands_on_the_same_frame() {
+        // counts frames in V2 until key 0 is held, then stops
+        let rom = [
+            0x61, 0x01, // 0x200: V1 = 1
+            0xF1, 0x15, // 0x202: delay timer = V1
+            0xE0, 0x9E, // 0x204: skip the next if key V0 (0) is held
+            0x12, 0x0A, // 0x206: jump 0x20A
+            0x12, 0x08, // 0x208: jump 0x208
+            0xF3, 0x07, // 0x20A: V3 = delay timer
+            0x33, 0x00, // 0x20C: skip the next if V3 is 0, i.e. a vblank's gone by
+            0x12, 0x04, // 0x20E: jump 0x204
+            0x72, 0x01, // 0x210: V2 += 1
+            0x12, 0x02, // 0x212: jump 0x202
+        ];
+        // the same program, just changed enough to reload
+        let reloaded = [&rom[..], &[0x00, 0xE0]].concat();
+        // held for a single frame, which the replay has to put in the same place
+        let mut keys = vec![0; 30];
+        keys[3] = 1;
+        let (before, after) = run_rewritten("replay", &rom, reloaded, keys);
+        assert_eq!(before.pc, 0x208);
+        assert_eq!(after.pc, 0x208);
+        assert_eq!(after.v[2], before.v[2]);
+    }
+
+    #[test]
+    fn single_thread_counts_down_before_drawing() {
+        let audio = RecordingAudio::default();
+        let mut sys = System::new();
+        sys.set_runtime(Runtime::Single);
+        {
+            let audio = audio.clone();
+            sys.set_frontend(move || Frontend {
+                input: Box::new(ScriptedInput::new(vec![0; 3])),
+                audio: Box::new(audio),
+                ..Frontend::null()
+            });
+        }
+        // a sound timer of 1 runs out at the end of the frame that set it, before anything hears it
+        sys.init_with_bytes(&[
+            0x60, 0x01, // 0x200: V0 = 1
+            0xF0, 0x18, // 0x202: sound timer = V0
+            0x12, 0x04, // 0x204: jump 0x204
+        ]).unwrap();
+
+        assert!(audio.frames.lock().unwrap().iter().all(|beeping| !*beeping));
+    }
+
+    #[test]
+    fn watch_options() {
+        assert_eq!(Watch::parse(""), Ok(Watch::default()));
+        assert_eq!(Watch::parse("replay,seed"), Ok(Watch { keep_seed: true, replay: true }));
+        assert!(Watch::parse("inputs").is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use std::io::{ Stdout, Write, stdout };
+use std::time::{ Duration, Instant };
+
+use crossterm::{ cursor, execute, queue, terminal };
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+    KeyboardEnhancementFlags, PushKeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+};
+use crossterm::style::{ Color, Print, SetBackgroundColor, SetForegroundColor, ResetColor };
+
+use crate::audio;
+use crate::frontend::{ Frontend, DisplayBackend, InputBackend, Input };
+use crate::keymap::KeyMap;
+use crate::palette::Palette;
+use crate::phosphor::{ AntiFlicker, Phosphor };
+use crate::screenshot::ScreenshotMode;
+use crate::vm::{ Screen, SCREEN_WIDTH, SCREEN_HEIGHT };
+
+// most terminals can't report key releases, so a key counts as held for this long after the last
+// press or auto-repeat it sent. it has to outlast the wait before a held key starts repeating,
+// which is usually 250 to 500ms
+const KEY_HOLD: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy)]
+pub enum TerminalMode {
+    HalfBlock, // one character per 1x2 pixels
+    Braille,   // one character per 2x4 pixels, for small terminals
+}
+
+/// Renders the screen into the terminal, for machines where sdl can't open a window. Raw mode and
+/// the alternate screen are left again on drop.
+pub struct Terminal {
+    out: Stdout,
+    mode: TerminalMode,
+    palette: Palette,
+    phosphor: Phosphor,
+    last_frame: Option<Screen>,
+    osd: Vec<String>,
+    osd_rows: usize, // status rows drawn last time, to clear once they're gone
+    reports_releases: bool,
+}
+
+/// Reads the keypad from stdin while a `Terminal` is up.
+pub struct TerminalInput {
+    keys: KeyMap,
+    pressed: [Option<Instant>; 16],
+    reports_releases: bool,
+}
+
+/// Sets up the terminal and builds a frontend around it, with sound if there's an audio device.
+pub fn frontend(mode: TerminalMode, keys: KeyMap) -> std::io::Result<Frontend> {
+    let (terminal, mut input) = Terminal::new(mode)?;
+    input.keys = keys;
+    Ok(Frontend {
+        display: Box::new(terminal),
+        input: Box::new(input),
+        audio: audio::default_backend(),
+    })
+}
+
+impl Terminal {
+    pub fn new(mode: TerminalMode) -> std::io::Result<(Self, TerminalInput)> {
+        let mut out = stdout();
+        terminal::enable_raw_mode()?;
+        execute!(out, terminal::EnterAlternateScreen, cursor::Hide, terminal::Clear(terminal::ClearType::All))?;
+
+        // terminals speaking the kitty keyboard protocol can tell us about releases directly
+        let reports_releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if reports_releases {
+            execute!(out, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+
+        let terminal = Self {
+            out,
+            mode,
+            palette: Palette::default(),
+            phosphor: Phosphor::new(AntiFlicker::Off),
+            last_frame: None,
+            osd: vec![],
+            osd_rows: 0,
+            reports_releases,
+        };
+        let input = TerminalInput {
+            keys: KeyMap::default(),
+            pressed: [None; 16],
+            reports_releases,
+        };
+        Ok((terminal, input))
+    }
+
+    pub fn draw_screen(&mut self, pixels: &Screen) -> std::io::Result<()> {
+        let pixels = &self.phosphor.apply_lit(pixels);
+        if self.last_frame.as_ref() == Some(pixels) {
+            return Ok(());
+        }
+
+        let lines = match self.mode {
+            TerminalMode::HalfBlock => Self::half_block_lines(pixels),
+            TerminalMode::Braille => Self::braille_lines(pixels),
+        };
+
+        let [r, g, b] = self.palette.foreground();
+        let foreground = Color::Rgb { r, g, b };
+        let [r, g, b] = self.palette.background();
+        let background = Color::Rgb { r, g, b };
+
+        queue!(self.out, cursor::MoveTo(0, 0), SetForegroundColor(foreground), SetBackgroundColor(background))?;
+        for line in &lines {
+            queue!(self.out, Print(line), cursor::MoveToNextLine(1))?;
+        }
+        queue!(self.out, ResetColor)?;
+
+        // the overlay goes in status rows under the screen, cut to its width
+        let width = lines[0].chars().count();
+        for row in 0..self.osd.len().max(self.osd_rows) {
+            let line: String = self.osd.get(row).map_or("", String::as_str).chars().take(width).collect();
+            queue!(self.out, Print(line), terminal::Clear(terminal::ClearType::UntilNewLine), cursor::MoveToNextLine(1))?;
+        }
+        self.osd_rows = self.osd.len();
+        self.out.flush()?;
+
+        self.last_frame = Some(*pixels);
+        Ok(())
+    }
+
+    // each character shows two vertically stacked pixels as an upper, lower or full block
+    fn half_block_lines(pixels: &Screen) -> Vec<String> {
+        let mut lines = vec![];
+        for rows in pixels.chunks(2) {
+            let mut line = String::new();
+            for (upper, lower) in rows[0].iter().zip(rows[1].iter()) {
+                line.push(match (upper, lower) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                });
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    fn braille_lines(pixels: &Screen) -> Vec<String> {
+        // braille dot bits, indexed by [row][column] within a 2x4 cell
+        const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+        let mut lines = vec![];
+        for y in (0..SCREEN_HEIGHT).step_by(4) {
+            let mut line = String::new();
+            for x in (0..SCREEN_WIDTH).step_by(2) {
+                let mut cell = 0x2800;
+                for (dy, row) in DOTS.iter().enumerate() {
+                    for (dx, dot) in row.iter().enumerate() {
+                        if pixels[y + dy][x + dx] {
+                            cell |= dot;
+                        }
+                    }
+                }
+                line.push(char::from_u32(cell).unwrap());
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+impl DisplayBackend for Terminal {
+    fn draw(&mut self, screen: &Screen) {
+        self.draw_screen(screen).expect("unable to draw to the terminal");
+    }
+
+    fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.last_frame = None;
+    }
+
+    fn set_anti_flicker(&mut self, mode: AntiFlicker) {
+        self.phosphor = Phosphor::new(mode);
+    }
+
+    fn set_osd(&mut self, lines: &[String]) {
+        if self.osd != lines {
+            self.osd = lines.to_vec();
+            self.last_frame = None;
+        }
+    }
+
+    fn set_title(&mut self, title: &str) {
+        // not every terminal shows a title, and it's not worth stopping for the ones that don't
+        let _ = execute!(self.out, terminal::SetTitle(title));
+    }
+}
+
+impl TerminalInput {
+    /// Drains pending terminal events and returns the keys currently considered held.
+    pub fn poll_input(&mut self) -> std::io::Result<Input> {
+        let mut input = Input::default();
+
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(KeyEvent { code, modifiers, kind, .. }) = event::read()? {
+                match code {
+                    KeyCode::Esc => input.quit = true,
+                    KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => input.quit = true,
+                    // no holding keys for fast forward, since not every terminal reports releases
+                    KeyCode::F(number) if kind != KeyEventKind::Release => match number {
+                        2 => input.next_theme = true,
+                        3 => input.toggle_mute = true,
+                        4 => input.cycle_osd = true,
+                        5 => input.reset = true,
+                        6 => input.toggle_pause = true,
+                        7 => input.advance_frame = true,
+                        8 => input.toggle_slow_motion = true,
+                        9 => input.toggle_recording = true,
+                        10 => input.toggle_fast_forward = true,
+                        12 => input.screenshot = Some(ScreenshotMode::Native),
+                        _ => {},
+                    },
+                    code => {
+                        let keys = Self::host_key(code).map_or(0, |host| self.keys.keys_for(&host));
+                        for (key, pressed) in self.pressed.iter_mut().enumerate() {
+                            if keys & 1 << key != 0 {
+                                *pressed = match kind {
+                                    KeyEventKind::Release => None,
+                                    _ => Some(Instant::now()),
+                                };
+                            }
+                        }
+                    },
+                }
+            }
+        }
+
+        for (key, pressed) in self.pressed.iter().enumerate() {
+            if let Some(at) = pressed {
+                if self.reports_releases || at.elapsed() < KEY_HOLD {
+                    input.keys |= 1 << key;
+                }
+            }
+        }
+
+        Ok(input)
+    }
+
+    // names keys the way sdl does, so one key map works for both frontends
+    fn host_key(code: KeyCode) -> Option<String> {
+        let name = match code {
+            KeyCode::Char(' ') => "Space",
+            KeyCode::Char(c) => return Some(c.to_ascii_uppercase().to_string()),
+            KeyCode::Up => "Up",
+            KeyCode::Down => "Down",
+            KeyCode::Left => "Left",
+            KeyCode::Right => "Right",
+            KeyCode::Enter => "Return",
+            KeyCode::Tab => "Tab",
+            KeyCode::Backspace => "Backspace",
+            _ => return None,
+        };
+        Some(name.to_string())
+    }
+}
+
+impl InputBackend for TerminalInput {
+    fn poll(&mut self) -> Input {
+        self.poll_input().expect("unable to read from the terminal")
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if self.reports_releases {
+            let _ = execute!(self.out, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.out, ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Terminal;
+    use crate::vm::VM;
+
+    #[test]
+    fn half_blocks() {
+        let mut screen = VM::create_screen();
+        screen[0][0] = true;
+        screen[1][1] = true;
+        screen[0][2] = true;
+        screen[1][2] = true;
+
+        let lines = Terminal::half_block_lines(&screen);
+        assert_eq!(lines.len(), 16);
+        assert!(lines[0].starts_with("▀▄█ "));
+        assert!(lines[1].chars().all(|c| c == ' '));
+    }
+
+    #[test]
+    fn braille() {
+        let mut screen = VM::create_screen();
+        screen[0][0] = true;
+        screen[3][1] = true;
+
+        let lines = Terminal::braille_lines(&screen);
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0].chars().count(), 32);
+        assert_eq!(lines[0].chars().next(), Some('\u{2881}'));
+    }
+}
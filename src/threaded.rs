@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use crate::system::Interfaces;
+use crate::vm::{ VM, OpCode, Status, VmError, MEMORY_BYTES };
+
+// longest run of straight-line instructions translated into a single block
+const MAX_BLOCK_LENGTH: usize = 64;
+
+type Op = Box<dyn Fn(&mut VM, &mut Interfaces) -> Result<(), VmError> + Send>;
+
+struct Block {
+    start: usize,
+    end: usize, // exclusive
+    ops: Vec<Op>,
+}
+
+/// Alternative to `VM::tick` for batch runs: straight-line code is translated into blocks of
+/// closures once and then replayed, rather than fetched and decoded instruction by instruction.
+/// Blocks are thrown away when the program writes over them.
+pub struct ThreadedBackend {
+    blocks: HashMap<u16, Block>,
+}
+
+impl Default for ThreadedBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ThreadedBackend {
+    pub fn new() -> Self {
+        Self {
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// Executes up to `budget` instructions, returning how many ran. Stops early if the vm is
+    /// terminated or waiting for the next frame, or at the first instruction that fails.
+    pub fn run(&mut self, vm: &mut VM, interfaces: &mut Interfaces, budget: usize) -> Result<usize, VmError> {
+        let mut executed = 0;
+
+        while executed < budget {
+            if let Status::Terminated = vm.status {
+                break;
+            }
+            if vm.waiting_for_vblank() {
+                break;
+            }
+
+            let block = match self.blocks.entry(vm.pc) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match Self::translate(vm, vm.pc as usize) {
+                    Some(block) => entry.insert(block),
+                    None => {
+                        // nothing translatable here, let the interpreter deal with it
+                        vm.tick(interfaces)?;
+                        executed += 1;
+                        self.invalidate(vm);
+                        continue;
+                    },
+                },
+            };
+
+            for op in block.ops.iter().take(budget - executed) {
+                vm.pc += 2;
+                op(vm, interfaces)?;
+                executed += 1;
+            }
+
+            self.invalidate(vm);
+        }
+
+        Ok(executed)
+    }
+
+    /// Drops every translated block, e.g. after loading a new rom into the vm.
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    fn invalidate(&mut self, vm: &mut VM) {
+        if let Some(written) = vm.take_written() {
+            self.blocks.retain(|_, block| block.end <= *written.start() || block.start > *written.end());
+        }
+    }
+
+    fn translate(vm: &VM, start: usize) -> Option<Block> {
+        let mut ops: Vec<Op> = vec![];
+        let mut address = start;
+
+        while ops.len() < MAX_BLOCK_LENGTH && address + 1 < MEMORY_BYTES {
+            let instruction = ((vm.memory[address] as u16) << 8) + vm.memory[address + 1] as u16;
+            let opcode = match VM::decode(instruction) {
+                Ok(opcode) => opcode,
+                Err(_) => break,
+            };
+            ops.push(Self::compile(opcode));
+            address += 2;
+
+            if Self::ends_block(opcode) {
+                break;
+            }
+        }
+
+        if ops.is_empty() {
+            return None;
+        }
+
+        Some(Block { start, end: address, ops })
+    }
+
+    // anything that can move the pc somewhere other than the next instruction, that writes to
+    // memory and so may have rewritten the rest of the block, or that may wait for the next frame
+    fn ends_block(opcode: OpCode) -> bool {
+        matches!(
+            opcode,
+            OpCode::Draw(_, _, _)
+                | OpCode::Jump(_)
+                | OpCode::JumpWithOffset(_)
+                | OpCode::EnterSubroutine(_)
+                | OpCode::ExitSubroutine
+                | OpCode::SkipIfMemoryEqual(_, _)
+                | OpCode::SkipIfMemoryNotEqual(_, _)
+                | OpCode::SkipIfRegisterEqual(_, _)
+                | OpCode::SkipIfRegisterNotEqual(_, _)
+                | OpCode::SkipIfKeyPressed(_)
+                | OpCode::SkipIfKeyNotPressed(_)
+                | OpCode::GetKeyBlocking(_)
+                | OpCode::StoreMemory(_)
+                | OpCode::SaveBCDConversionToMemory(_)
+        )
+    }
+
+    // the most common register ops get their own closure, everything else goes back through the
+    // interpreter's execute so the two can't drift apart
+    fn compile(opcode: OpCode) -> Op {
+        match opcode {
+            OpCode::SetRegister(x, value) => Box::new(move |vm, _| {
+                vm.registers[x as usize] = value;
+                Ok(())
+            }),
+            OpCode::AddRegister(x, value) => Box::new(move |vm, _| {
+                vm.registers[x as usize] += value;
+                Ok(())
+            }),
+            OpCode::SetXtoY(x, y) => Box::new(move |vm, _| {
+                vm.registers[x as usize] = vm.registers[y as usize];
+                Ok(())
+            }),
+            OpCode::SetIndexRegister(value) => Box::new(move |vm, _| {
+                vm.index = value;
+                Ok(())
+            }),
+            OpCode::AddXToIndexRegister(x) => Box::new(move |vm, _| {
+                vm.index += vm.registers[x as usize] as u16;
+                Ok(())
+            }),
+            opcode => Box::new(move |vm, interfaces| vm.execute(opcode, interfaces)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{ Rng, SeedableRng };
+    use rand::rngs::StdRng;
+
+    use super::ThreadedBackend;
+    use crate::system::Interfaces;
+    use crate::vm::{ VM, Quirks };
+
+    const ROMS: u64 = 300;
+    const STEPS: usize = 2_000;
+
+    // random but mostly well formed programs: jumps and calls land on instructions inside the
+    // rom, and the index register stays clear of the end of memory
+    fn random_rom(rng: &mut StdRng) -> Vec<u8> {
+        let length = rng.gen_range(8..128);
+        let mut rom = vec![];
+
+        for _ in 0..length {
+            let x = rng.gen_range(0..16u16);
+            let y = rng.gen_range(0..16u16);
+            let nn = rng.gen::<u8>() as u16;
+            let target = 0x200 + rng.gen_range(0..length) * 2;
+            let data = 0x200 + rng.gen_range(0..0x400u16);
+
+            // returns and computed jumps are kept rare, as most of them end the run
+            let instruction = match rng.gen_range(0..34) {
+                0 => 0x00E0,
+                1 if rng.gen_range(0..8) == 0 => 0x00EE,
+                2 => 0x1000 | target,
+                3 => 0x2000 | target,
+                4 => 0x3000 | x << 8 | nn,
+                5 => 0x4000 | x << 8 | nn,
+                6 => 0x5000 | x << 8 | y << 4,
+                7 | 8 => 0x6000 | x << 8 | nn,
+                9 | 10 => 0x7000 | x << 8 | nn,
+                11 => 0x8000 | x << 8 | y << 4,
+                12 => 0x8001 | x << 8 | y << 4,
+                13 => 0x8002 | x << 8 | y << 4,
+                14 => 0x8003 | x << 8 | y << 4,
+                15 => 0x8004 | x << 8 | y << 4,
+                16 => 0x8005 | x << 8 | y << 4,
+                17 => 0x8006 | x << 8 | y << 4,
+                18 => 0x8007 | x << 8 | y << 4,
+                19 => 0x800E | x << 8 | y << 4,
+                20 => 0x9000 | x << 8 | y << 4,
+                21 => 0xA000 | data,
+                22 if rng.gen_range(0..8) == 0 => 0xB000 | (target - 0x100),
+                23 => 0xC000 | x << 8 | nn,
+                24 => 0xD000 | x << 8 | y << 4 | rng.gen_range(0..16u16),
+                25 => 0xE09E | x << 8,
+                26 => 0xE0A1 | x << 8,
+                27 => 0xF007 | x << 8,
+                28 => 0xF015 | x << 8,
+                29 => 0xF018 | x << 8,
+                30 => 0xF033 | x << 8,
+                31 => 0xF055 | x << 8,
+                32 => 0xF065 | x << 8,
+                33 => 0xF029 | x << 8,
+                _ => 0x6000 | x << 8 | nn,
+            };
+            rom.push((instruction >> 8) as u8);
+            rom.push(instruction as u8);
+        }
+
+        rom
+    }
+
+    fn boot(rom: &[u8], keys: u16, quirks: Quirks) -> (VM, Interfaces) {
+        let mut vm = VM::new();
+        vm.seed(0);
+        vm.quirks = quirks;
+        vm.load_rom(rom.to_vec());
+        let mut interfaces = Interfaces::new();
+        interfaces.keys = keys;
+        (vm, interfaces)
+    }
+
+    fn assert_same(rom: u64, step: usize, a: &(VM, Interfaces), b: &(VM, Interfaces)) {
+        let context = format!("rom {} after {} steps", rom, step);
+        assert_eq!(a.0.pc, b.0.pc, "pc differs, {}", context);
+        assert_eq!(a.0.index, b.0.index, "index differs, {}", context);
+        assert_eq!(a.0.registers, b.0.registers, "registers differ, {}", context);
+        assert_eq!(a.0.stack, b.0.stack, "stack differs, {}", context);
+        assert_eq!(a.0.stack_pointer, b.0.stack_pointer, "stack pointer differs, {}", context);
+        assert_eq!(a.0.delay_timer, b.0.delay_timer, "delay timer differs, {}", context);
+        assert!(a.0.memory == b.0.memory, "memory differs, {}", context);
+        assert_eq!(a.1.screen, b.1.screen, "screen differs, {}", context);
+        assert_eq!(a.1.sound_timer, b.1.sound_timer, "sound timer differs, {}", context);
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let mut rng = StdRng::seed_from_u64(0xC8);
+
+        for rom_number in 0..ROMS {
+            let rom = random_rom(&mut rng);
+            let keys: u16 = rng.gen();
+            // every quirk but vblank, which makes the interpreter sit out ticks
+            let quirks = Quirks {
+                shift: rng.gen(),
+                memory_increment_by_x: rng.gen(),
+                memory_leave_i_unchanged: rng.gen(),
+                wrap: rng.gen(),
+                jump: rng.gen(),
+                vblank: false,
+                logic: rng.gen(),
+            };
+
+            // run the interpreter until it either finishes or stops with an error
+            let mut interpreted = boot(&rom, keys, quirks);
+            let mut completed = 0;
+            let mut stopped = None;
+            while completed < STEPS {
+                let (vm, interfaces) = &mut interpreted;
+                if let Err(error) = vm.tick(interfaces) {
+                    stopped = Some(error);
+                    break;
+                }
+                completed += 1;
+            }
+
+            // the threaded backend should agree at every point we stop it, in uneven chunks, and
+            // stop with the same error
+            let mut threaded = boot(&rom, keys, quirks);
+            let mut backend = ThreadedBackend::new();
+            let mut executed = 0;
+            while executed < completed {
+                let chunk = rng.gen_range(1..200).min(completed - executed);
+                assert_eq!(backend.run(&mut threaded.0, &mut threaded.1, chunk), Ok(chunk));
+                executed += chunk;
+            }
+            if let Some(error) = stopped {
+                let (vm, interfaces) = &mut threaded;
+                assert_eq!(backend.run(vm, interfaces, 1), Err(error), "rom {} should fail at step {}", rom_number, completed);
+            }
+            assert_same(rom_number, completed, &interpreted, &threaded);
+        }
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let rom = [
+            0x63, 0x42, // 0x200: V3 = 0x42
+            0x60, 0x63, // 0x202: V0 = 0x63
+            0x61, 0x07, // 0x204: V1 = 0x07
+            0xA2, 0x00, // 0x206: I = 0x200
+            0xF1, 0x55, // 0x208: store V0..V1, rewriting 0x200 to V3 = 0x07
+            0x12, 0x00, // 0x20A: jump 0x200
+        ];
+        let (mut vm, mut interfaces) = boot(&rom, 0, Quirks::default());
+        let mut backend = ThreadedBackend::new();
+
+        backend.run(&mut vm, &mut interfaces, 6).unwrap();
+        assert_eq!(vm.registers[3], 0x42);
+
+        backend.run(&mut vm, &mut interfaces, 1).unwrap();
+        assert_eq!(vm.registers[3], 0x07);
+    }
+}
@@ -1,9 +1,16 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use crate::memory::{ InterpreterWrites, MemoryFill, MemoryMap };
 use crate::system::{ Interfaces };
 
-const MEMORY_BYTES: usize = 4096;
+pub const MEMORY_BYTES: usize = 4096;
 pub const SCREEN_WIDTH: usize = 64;
 pub const SCREEN_HEIGHT: usize = 32;
-const FONT: [[u8; 5]; 16] = [
+pub const FONT: [[u8; 5]; 16] = [
     [0xF0, 0x90, 0x90, 0x90, 0xF0], // 0
     [0x20, 0x60, 0x20, 0x20, 0x70], // 1
     [0xF0, 0x10, 0xF0, 0x80, 0xF0], // 2
@@ -40,8 +47,93 @@ macro_rules! poc {
     };
 }
 
-// options
-const LOAD_Y_BEFORE_SHIFT: bool = true; // used in bitshift, loads reg y into x before shifting x
+/// Behaviours that differ between interpreters, named as in the community chip-8 database. The
+/// defaults are what this vm has always done.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    pub shift: bool, // 8XY6/8XYE shift vx in place instead of loading vy into it first
+    pub memory_increment_by_x: bool, // FX55/FX65 add x to the index register
+    pub memory_leave_i_unchanged: bool, // FX55/FX65 leave the index register alone, otherwise it's incremented by x + 1
+    pub wrap: bool, // sprites wrap around the edges of the screen instead of being clipped
+    pub jump: bool, // BXNN jumps to XNN + vx instead of NNN + v0
+    pub vblank: bool, // DXYN waits for the start of the next frame
+    pub logic: bool, // 8XY1/8XY2/8XY3 reset vf
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift: false,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
+
+/// What a call does when the stack is already full.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StackOverflow {
+    #[default]
+    Error, // stop with VmError::StackOverflow
+    Wrap, // start again from the bottom, overwriting the oldest return addresses
+}
+
+impl StackOverflow {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "error" => Ok(Self::Error),
+            "wrap" => Ok(Self::Wrap),
+            _ => Err(format!("unknown stack overflow behaviour {}, expected error or wrap", name)),
+        }
+    }
+}
+
+// where the vip keeps return addresses, growing down from the end
+pub const VIP_STACK: RangeInclusive<u16> = 0xEA0..=0xECF;
+
+/// How deep subroutine calls can go and where the return addresses live.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackConfig {
+    pub depth: usize,
+    pub overflow: StackOverflow,
+    pub in_memory: bool, // keep the stack in VIP_STACK like the vip, where roms can see and clobber it
+}
+
+impl Default for StackConfig {
+    fn default() -> Self {
+        Self { depth: 16, overflow: StackOverflow::Error, in_memory: false }
+    }
+}
+
+impl StackConfig {
+    /// The cosmac vip's twelve levels in ram.
+    pub fn vip() -> Self {
+        Self { depth: 12, overflow: StackOverflow::Error, in_memory: true }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let room = (VIP_STACK.end() - VIP_STACK.start() + 1) as usize / 2;
+        if self.depth == 0 {
+            Err("stack depth must be at least 1".to_string())
+        } else if self.in_memory && self.depth > room {
+            Err(format!("a stack in memory holds at most {} return addresses", room))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A call that hasn't returned yet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackFrame {
+    pub call_site: u16, // address of the 2NNN
+    pub return_address: u16,
+    pub subroutine: Option<u16>, // NNN, unless the call site has since been overwritten
+}
 
 pub enum Status {
     Active,
@@ -50,15 +142,58 @@ pub enum Status {
 
 pub type Screen = [[bool; SCREEN_WIDTH]; SCREEN_HEIGHT];
 
+/// Why the vm can't carry on running a rom. Addresses are of the instruction at fault.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmError {
+    UnknownOpcode { opcode: u16, address: u16 },
+    StackUnderflow { address: u16 }, // a return with nothing to return to
+    StackOverflow { address: u16 }, // a call deeper than the stack goes
+    ProtectedWrite { address: u16, target: u16 }, // a write into the interpreter area while it's protected
+    OutOfMemory { address: u16 }, // the pc, or an access through the index register, ran off the end of memory
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::UnknownOpcode { opcode, address } => write!(f, "unknown opcode {:#06X} at {:#05X}", opcode, address),
+            VmError::StackUnderflow { address } => write!(f, "return with an empty stack at {:#05X}", address),
+            VmError::StackOverflow { address } => write!(f, "call with a full stack at {:#05X}", address),
+            VmError::ProtectedWrite { address, target } => write!(f, "write to {:#05X} in the interpreter area at {:#05X}", target, address),
+            VmError::OutOfMemory { address } => write!(f, "ran off the end of memory at {:#05X}", address),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// The cpu's registers at a moment, for showing while debugging.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub index: u16,
+    pub v: [u8; 16],
+    pub delay_timer: u8,
+}
+
 pub struct VM {
-    memory: [u8; MEMORY_BYTES],
+    pub(crate) memory: [u8; MEMORY_BYTES],
+    decoded: [Option<OpCode>; MEMORY_BYTES], // predecoded instructions keyed by address
+    decode_cache: bool,
+    written: Option<RangeInclusive<usize>>, // addresses written since the last take_written
+    rng: StdRng,
+    waiting_for_vblank: bool,
+    pub quirks: Quirks,
     pub screen: Screen,
     pub delay_timer: u8,
     pub status: Status,
-    pc: u16,
-    index: u16,
-    stack: Vec<u16>,
-    registers: [u8; 16],
+    pub(crate) pc: u16,
+    pub(crate) index: u16,
+    pub(crate) stack: Vec<u16>, // one slot per level, unused when the stack is in memory
+    pub(crate) stack_pointer: usize,
+    stack_len: usize,
+    stack_config: StackConfig,
+    memory_map: MemoryMap,
+    pub(crate) registers: [u8; 16],
 }
 
 impl Clone for Status {
@@ -88,29 +223,97 @@ impl Clone for VM {
 }
 */
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> VM {
         let mut sys = VM {
-            memory: [0 as u8; MEMORY_BYTES],
+            memory: [0; MEMORY_BYTES],
+            decoded: [None; MEMORY_BYTES],
+            decode_cache: true,
+            written: None,
+            rng: StdRng::from_entropy(),
+            waiting_for_vblank: false,
+            quirks: Quirks::default(),
             screen: Self::create_screen(),
             index: 0,
             pc: 0x200,
-            stack: vec![],
-            registers: [0 as u8; 16],
+            stack: vec![0; StackConfig::default().depth],
+            stack_pointer: 0,
+            stack_len: 0,
+            stack_config: StackConfig::default(),
+            memory_map: MemoryMap::default(),
+            registers: [0; 16],
             delay_timer: 0,
             status: Status::Active,
         };
 
-        // initialize font
-        let mut index: usize = 0;
-        for letter in FONT {
-            for byte in letter {
-                sys.memory[index] = byte;
-                index += 1;
-            }
+        sys.set_memory_map(MemoryMap::default()).expect("the default memory map is valid");
+        sys
+    }
+
+    /// Enables or disables the predecoded instruction cache. The cache is on by default; turning it
+    /// off makes every tick fetch and decode from memory, which is mostly useful for benchmarking.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = enabled;
+        self.decoded = [None; MEMORY_BYTES];
+    }
+
+    /// Changes the stack's size and overflow behaviour, emptying it.
+    pub fn set_stack_config(&mut self, config: StackConfig) {
+        self.stack_config = config;
+        self.stack = vec![0; config.depth];
+        self.stack_pointer = 0;
+        self.stack_len = 0;
+        self.memory_map.stack_in_memory = config.in_memory;
+    }
+
+    /// Refills memory and writes the font where `map` puts it, so this goes before loading a rom,
+    /// and after seeding if the fill is random. Fails if the map doesn't validate.
+    pub fn set_memory_map(&mut self, map: MemoryMap) -> Result<(), String> {
+        map.validate()?;
+        for address in 0..MEMORY_BYTES {
+            let byte = match map.fill {
+                MemoryFill::Zero => 0,
+                MemoryFill::Byte(byte) => byte,
+                MemoryFill::Random => self.rng.gen(),
+            };
+            self.write_memory(address, byte);
         }
+        for (offset, byte) in FONT.iter().flatten().enumerate() {
+            self.write_memory(map.font_address as usize + offset, *byte);
+        }
+        self.memory_map = MemoryMap { program: self.memory_map.program.clone(), stack_in_memory: self.stack_config.in_memory, ..map };
+        Ok(())
+    }
 
-        sys
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    /// The calls waiting to return, outermost first.
+    pub fn call_stack(&self) -> Vec<StackFrame> {
+        let depth = self.stack_config.depth;
+        (0..self.stack_len)
+            .map(|level| (self.stack_pointer + depth - self.stack_len + level) % depth)
+            .map(|level| {
+                let return_address = self.read_stack(level);
+                let call_site = return_address.wrapping_sub(2);
+                let instruction = self.memory.get(call_site as usize..call_site as usize + 2)
+                    .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+                let subroutine = instruction.filter(|instruction| instruction & 0xF000 == 0x2000).map(|instruction| instruction & 0x0FFF);
+                StackFrame { call_site, return_address, subroutine }
+            })
+            .collect()
+    }
+
+    /// Reseeds the generator behind CXNN so that runs are reproducible.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
     }
 
     pub fn create_screen() -> Screen {
@@ -118,17 +321,138 @@ impl VM {
     }
 
     pub fn load_rom(&mut self, data: Vec<u8>) {
-        let mut index = 0x0200 as usize;
-        for byte in data {
-            self.memory[index] = byte;
-            index += 1;
+        self.load_rom_at(0x200, data);
+    }
+
+    /// Loads a rom somewhere other than 0x200, and starts running it from there.
+    pub fn load_rom_at(&mut self, address: u16, data: Vec<u8>) {
+        self.memory_map.program = address..address.saturating_add(data.len() as u16);
+        for (offset, byte) in data.into_iter().enumerate() {
+            self.write_memory(address as usize + offset, byte);
         }
+        self.pc = address;
+    }
+
+    /// Called at the start of every frame, which is what the vblank quirk waits for.
+    pub fn vblank(&mut self) {
+        self.waiting_for_vblank = false;
+    }
+
+    pub(crate) fn waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
     }
 
-    pub fn tick(&mut self, interfaces: &mut Interfaces) {
-        let opcode = self.fetch();
-        let opcode = Self::decode(opcode).unwrap();
-        self.execute(opcode, interfaces);
+    pub fn tick(&mut self, interfaces: &mut Interfaces) -> Result<(), VmError> {
+        if self.waiting_for_vblank {
+            return Ok(());
+        }
+
+        let pc = self.pc as usize;
+        if pc + 1 >= MEMORY_BYTES {
+            return Err(VmError::OutOfMemory { address: self.pc });
+        }
+        let opcode = match self.decoded[pc] {
+            Some(opcode) => {
+                self.pc += 2;
+                opcode
+            },
+            None => {
+                let instruction = self.fetch();
+                let opcode = Self::decode(instruction)
+                    .map_err(|_| VmError::UnknownOpcode { opcode: instruction, address: pc as u16 })?;
+                if self.decode_cache {
+                    self.decoded[pc] = Some(opcode);
+                }
+                opcode
+            },
+        };
+        self.execute(opcode, interfaces)
+    }
+
+    // all writes to memory go through here so that cached instructions covering the byte are dropped
+    fn write_memory(&mut self, address: usize, byte: u8) {
+        self.memory[address] = byte;
+        self.decoded[address] = None;
+        if address > 0 {
+            self.decoded[address - 1] = None;
+        }
+        self.written = match self.written.take() {
+            Some(range) => Some(*range.start().min(&address)..=*range.end().max(&address)),
+            None => Some(address..=address),
+        };
+    }
+
+    // the address holding a level of the stack when it's in memory
+    fn stack_address(level: usize) -> usize {
+        *VIP_STACK.end() as usize + 1 - 2 * (level + 1)
+    }
+
+    fn read_stack(&self, level: usize) -> u16 {
+        if self.stack_config.in_memory {
+            let address = Self::stack_address(level);
+            u16::from_be_bytes([self.memory[address], self.memory[address + 1]])
+        } else {
+            self.stack[level]
+        }
+    }
+
+    // the stack's a ring of `depth` slots, with `stack_pointer` at the next one free and
+    // `stack_len` of the slots before it in use. it only goes round when it's set to wrap
+    fn push(&mut self, return_address: u16) -> Result<(), VmError> {
+        let depth = self.stack_config.depth;
+        if self.stack_len == depth && self.stack_config.overflow == StackOverflow::Error {
+            return Err(VmError::StackOverflow { address: return_address - 2 });
+        }
+        if self.stack_config.in_memory {
+            let address = Self::stack_address(self.stack_pointer);
+            let [high, low] = return_address.to_be_bytes();
+            self.write_memory(address, high);
+            self.write_memory(address + 1, low);
+        } else {
+            self.stack[self.stack_pointer] = return_address;
+        }
+        self.stack_pointer = (self.stack_pointer + 1) % depth;
+        self.stack_len = (self.stack_len + 1).min(depth);
+        Ok(())
+    }
+
+    fn pop(&mut self, address: u16) -> Result<u16, VmError> {
+        let depth = self.stack_config.depth;
+        if self.stack_len == 0 && self.stack_config.overflow == StackOverflow::Error {
+            return Err(VmError::StackUnderflow { address });
+        }
+        self.stack_pointer = (self.stack_pointer + depth - 1) % depth;
+        self.stack_len = self.stack_len.saturating_sub(1);
+        Ok(self.read_stack(self.stack_pointer))
+    }
+
+    // the address `offset` bytes on from the index register, as long as it's in memory
+    fn index_address(&self, offset: u16) -> Result<usize, VmError> {
+        let address = self.index as usize + offset as usize;
+        if address >= MEMORY_BYTES {
+            return Err(VmError::OutOfMemory { address: self.pc - 2 });
+        }
+        Ok(address)
+    }
+
+    // writes made by the rom itself, which the memory map may log or refuse
+    fn store(&mut self, address: usize, byte: u8) -> Result<(), VmError> {
+        if self.memory_map.interpreter_owned(address as u16) {
+            let instruction = self.pc - 2;
+            match self.memory_map.interpreter_writes {
+                InterpreterWrites::Allow => {},
+                InterpreterWrites::Log => eprintln!("{:#05X} wrote {:#04X} to {:#05X} in the interpreter area", instruction, byte, address),
+                InterpreterWrites::Protect => return Err(VmError::ProtectedWrite { address: instruction, target: address as u16 }),
+            }
+        }
+        self.write_memory(address, byte);
+        Ok(())
+    }
+
+    /// Returns the span of addresses written since the last call, for anything outside the VM
+    /// that holds on to translated code.
+    pub(crate) fn take_written(&mut self) -> Option<RangeInclusive<usize>> {
+        self.written.take()
     }
 
     fn fetch(&mut self) -> u16 {
@@ -194,7 +518,8 @@ impl VM {
         Err(format!("failed to parse opcode {:#06X}", opcode))
     }
 
-    fn execute(&mut self, opcode: OpCode, interfaces: &mut Interfaces) {
+    // runs an instruction, with the pc already moved past it
+    pub(crate) fn execute(&mut self, opcode: OpCode, interfaces: &mut Interfaces) -> Result<(), VmError> {
         match opcode {
             OpCode::AddRegister(address, value) => {
                 self.registers[address as usize] += value;
@@ -215,47 +540,53 @@ impl VM {
 
                 for n in 0..height {
                     let n = n as usize;
-                    // break out if off edge of screen
-                    if y + n >= SCREEN_HEIGHT {
+                    // break out if off edge of screen, unless wrapping round
+                    if y + n >= SCREEN_HEIGHT && !self.quirks.wrap {
                         break;
                     }
+                    let row = (y + n) % SCREEN_HEIGHT;
 
                     // grab sprite row from memory
-                    let sprite_byte = self.memory[(self.index + n as u16) as usize];
+                    let sprite_byte = self.memory[self.index_address(n as u16)?];
 
                     for bit in 0..8 {
-                        // break out if off edge of screen
-                        if x + bit >= SCREEN_WIDTH {
+                        // break out if off edge of screen, unless wrapping round
+                        if x + bit >= SCREEN_WIDTH && !self.quirks.wrap {
                             break;
                         }
+                        let column = (x + bit) % SCREEN_WIDTH;
 
                         // new bit is pixel in row
                         let new = (sprite_byte & (0x80 >> bit)) != 0;
 
                         // current is whatever is on screen
-                        let current = interfaces.screen[y as usize + n][x as usize + bit];
+                        let current = interfaces.screen[row][column];
 
                         // if new and current are both set, invert and set flag register to 1
                         if new && current {
                             self.registers[0xF] = 1;
-                            interfaces.screen[y + n][x + bit] = false;
+                            interfaces.screen[row][column] = false;
 
                         // if screen isn't on but is on on sprite, then turn it on
                         } else if new && !current {
-                            interfaces.screen[y + n][x + bit] = true;
+                            interfaces.screen[row][column] = true;
                         }
                     }
                 }
+
+                if self.quirks.vblank {
+                    self.waiting_for_vblank = true;
+                }
             },
             OpCode::Jump(address) => {
                 self.pc = address;
             },
             OpCode::EnterSubroutine(address) => {
-                self.stack.push(self.pc);
+                self.push(self.pc)?;
                 self.pc = address;
             },
             OpCode::ExitSubroutine => {
-                self.pc = self.stack.pop().unwrap();
+                self.pc = self.pop(self.pc - 2)?;
             },
             OpCode::SetIndexRegister(value) => {
                 self.index = value;
@@ -285,27 +616,36 @@ impl VM {
             },
             OpCode::BitwiseOr(x, y) => {
                 self.registers[x as usize] |= self.registers[y as usize];
+                if self.quirks.logic {
+                    self.registers[0xF] = 0;
+                }
             },
             OpCode::BitwiseAnd(x, y) => {
                 self.registers[x as usize] &= self.registers[y as usize];
+                if self.quirks.logic {
+                    self.registers[0xF] = 0;
+                }
             },
             OpCode::BitwiseXor(x, y) => {
                 self.registers[x as usize] ^= self.registers[y as usize];
+                if self.quirks.logic {
+                    self.registers[0xF] = 0;
+                }
             },
             OpCode::ShiftRight(x, y) => {
-                if LOAD_Y_BEFORE_SHIFT {
+                if !self.quirks.shift {
                     self.registers[x as usize] = self.registers[y as usize];
                 }
                 let value = self.registers[x as usize];
-                self.registers[0xF as usize] = value & 1;
+                self.registers[0xF] = value & 1;
                 self.registers[x as usize] >>= 1;
             },
             OpCode::ShiftLeft(x, y) => {
-                if LOAD_Y_BEFORE_SHIFT {
+                if !self.quirks.shift {
                     self.registers[x as usize] = self.registers[y as usize];
                 }
                 let value = self.registers[x as usize];
-                self.registers[0xF as usize] = (value & 0x80 == 0x80) as u8;
+                self.registers[0xF] = (value & 0x80 == 0x80) as u8;
                 self.registers[x as usize] <<= 1;
             },
             OpCode::AddYtoX(x, y) => {
@@ -315,14 +655,14 @@ impl VM {
                 self.registers[x as usize] -= self.registers[y as usize];
             },
             OpCode::SubtractXfromY(x, y) => {
-                self.registers[x as usize] =
-                    self.registers[x as usize] - self.registers[y as usize];
+                self.registers[x as usize] -= self.registers[y as usize];
             },
             OpCode::JumpWithOffset(address) => {
-                self.pc = address + self.registers[0] as u16;
+                let offset = if self.quirks.jump { address >> 8 } else { 0 };
+                self.pc = address + self.registers[offset as usize] as u16;
             },
             OpCode::Random(x, mask) => {
-                let val: u8 = rand::random();
+                let val: u8 = self.rng.gen();
                 self.registers[x as usize] = mask & val;
             },
             OpCode::SkipIfKeyPressed(x) => {
@@ -337,22 +677,24 @@ impl VM {
             },
             OpCode::StoreMemory(x) => {
                 for i in 0..x + 1 {
-                    self.memory[(self.index + i as u16) as usize] = self.registers[i as usize];
+                    self.store(self.index_address(i as u16)?, self.registers[i as usize])?;
                 }
+                self.increment_index_after_memory(x);
             },
             OpCode::LoadMemory(x) => {
                 for i in 0..x + 1 {
-                    self.registers[i as usize] = self.memory[(self.index + i as u16) as usize];
+                    self.registers[i as usize] = self.memory[self.index_address(i as u16)?];
                 }
+                self.increment_index_after_memory(x);
             },
             OpCode::SaveBCDConversionToMemory(x) => {
                 let value = self.registers[x as usize];
-                let hundreds = (value / 100) as u8;
-                let tens = ((value - (hundreds * 100)) / 10) as u8;
-                let ones = (value - hundreds * 100 - tens * 10) as u8;
-                self.memory[self.index as usize] = hundreds;
-                self.memory[(self.index + 1) as usize] = tens;
-                self.memory[(self.index + 2) as usize] = ones;
+                let hundreds = value / 100;
+                let tens = (value - (hundreds * 100)) / 10;
+                let ones = value - hundreds * 100 - tens * 10;
+                self.store(self.index_address(0)?, hundreds)?;
+                self.store(self.index_address(1)?, tens)?;
+                self.store(self.index_address(2)?, ones)?;
             },
             OpCode::SetSoundTimerValue(x) => {
                 interfaces.sound_timer = self.registers[x as usize];
@@ -372,9 +714,27 @@ impl VM {
                 self.index += self.registers[x as usize] as u16;
             },
             OpCode::SetIndexToFontCharacter(x) => {
-                self.index = x as u16 * FONT[0].len() as u16;
+                self.index = self.memory_map.font_address + x as u16 * FONT[0].len() as u16;
             },
         };
+        Ok(())
+    }
+
+    fn increment_index_after_memory(&mut self, x: u8) {
+        if self.quirks.memory_increment_by_x {
+            self.index += x as u16;
+        } else if !self.quirks.memory_leave_i_unchanged {
+            self.index += x as u16 + 1;
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            index: self.index,
+            v: self.registers,
+            delay_timer: self.delay_timer,
+        }
     }
 
     pub fn terminate(&mut self) {
@@ -382,7 +742,7 @@ impl VM {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[derive(PartialEq)]
 pub enum OpCode {
     //// COMPLETE
@@ -496,4 +856,185 @@ mod tests {
         decode_fx07 => [super::VM::decode, 0xF107, Ok(super::OpCode::GetDelayTimerValue(1))],
         decode_fx15 => [super::VM::decode, 0xF215, Ok(super::OpCode::SetDelayTimerValue(2))],
     );
+
+    #[test]
+    fn decode_cache_invalidated_by_store() {
+        let mut vm = super::VM::new();
+        let mut interfaces = crate::system::Interfaces::new();
+        vm.load_rom(vec![
+            0x63, 0x42, // 0x200: V3 = 0x42
+            0x60, 0x63, // 0x202: V0 = 0x63
+            0x61, 0x07, // 0x204: V1 = 0x07
+            0xA2, 0x00, // 0x206: I = 0x200
+            0xF1, 0x55, // 0x208: store V0..V1, rewriting 0x200 to V3 = 0x07
+            0x12, 0x00, // 0x20A: jump 0x200
+        ]);
+
+        for _ in 0..6 {
+            vm.tick(&mut interfaces).unwrap();
+        }
+        assert_eq!(vm.registers[3], 0x42);
+
+        vm.tick(&mut interfaces).unwrap();
+        assert_eq!(vm.registers[3], 0x07);
+    }
+
+    #[test]
+    fn reports_errors() {
+        use super::VmError;
+
+        let mut vm = super::VM::new();
+        let mut interfaces = crate::system::Interfaces::new();
+        vm.load_rom(vec![
+            0x22, 0x06, // 0x200: call 0x206
+            0xF1, 0xFF, // 0x202: not an instruction
+            0x00, 0x00,
+            0x00, 0xEE, // 0x206: return
+        ]);
+        vm.tick(&mut interfaces).unwrap();
+        vm.tick(&mut interfaces).unwrap();
+        let error = vm.tick(&mut interfaces).unwrap_err();
+        assert_eq!(error, VmError::UnknownOpcode { opcode: 0xF1FF, address: 0x202 });
+        assert_eq!(error.to_string(), "unknown opcode 0xF1FF at 0x202");
+
+        vm.load_rom_at(0x206, vec![0x00, 0xEE]);
+        assert_eq!(vm.tick(&mut interfaces), Err(VmError::StackUnderflow { address: 0x206 }));
+        vm.load_rom_at(0xFFF, vec![0x00]);
+        assert_eq!(vm.tick(&mut interfaces), Err(VmError::OutOfMemory { address: 0xFFF }));
+    }
+
+    #[test]
+    fn index_past_memory() {
+        use super::VmError;
+
+        // each runs from I = 0xFFF, where only the first byte is in memory
+        for (instruction, fails) in [(0xD015, true), (0xD011, false), (0xF165, true), (0xF055, false), (0xF033, true)] {
+            let mut vm = super::VM::new();
+            let mut interfaces = crate::system::Interfaces::new();
+            let [high, low] = u16::to_be_bytes(instruction);
+            vm.load_rom(vec![0xAF, 0xFF, high, low]);
+            vm.tick(&mut interfaces).unwrap();
+            let expected = if fails { Err(VmError::OutOfMemory { address: 0x202 }) } else { Ok(()) };
+            assert_eq!(vm.tick(&mut interfaces), expected, "{:#06X}", instruction);
+        }
+
+        // FX1E can take the index past the end too
+        let mut vm = super::VM::new();
+        let mut interfaces = crate::system::Interfaces::new();
+        vm.load_rom(vec![0xAF, 0xF0, 0x60, 0x20, 0xF0, 0x1E, 0xF0, 0x65]);
+        for _ in 0..3 {
+            vm.tick(&mut interfaces).unwrap();
+        }
+        assert_eq!(vm.tick(&mut interfaces), Err(VmError::OutOfMemory { address: 0x206 }));
+    }
+
+    #[test]
+    fn stack() {
+        use super::{ StackConfig, StackFrame, StackOverflow, VmError };
+
+        let mut vm = super::VM::new();
+        let mut interfaces = crate::system::Interfaces::new();
+        let rom = vec![
+            0x22, 0x04, // 0x200: call 0x204
+            0x00, 0x00,
+            0x22, 0x04, // 0x204: call 0x204, forever
+        ];
+        vm.load_rom(rom.clone());
+        vm.set_stack_config(StackConfig { depth: 2, ..StackConfig::default() });
+        vm.tick(&mut interfaces).unwrap();
+        vm.tick(&mut interfaces).unwrap();
+        assert_eq!(vm.call_stack(), [
+            StackFrame { call_site: 0x200, return_address: 0x202, subroutine: Some(0x204) },
+            StackFrame { call_site: 0x204, return_address: 0x206, subroutine: Some(0x204) },
+        ]);
+        assert_eq!(vm.tick(&mut interfaces), Err(VmError::StackOverflow { address: 0x204 }));
+
+        vm.load_rom(rom);
+        vm.set_stack_config(StackConfig { overflow: StackOverflow::Wrap, ..StackConfig::vip() });
+        for _ in 0..14 {
+            vm.tick(&mut interfaces).unwrap();
+        }
+        // the thirteenth call went back to the bottom, over the call from 0x200
+        assert_eq!(vm.call_stack().len(), 12);
+        assert!(vm.call_stack().iter().all(|frame| frame.return_address == 0x206));
+        assert_eq!(vm.memory[0xECE..0xED0], [0x02, 0x06]);
+        assert_eq!(vm.memory[0xEB8..0xEBA], [0x02, 0x06]);
+
+        assert!(StackConfig { depth: 25, ..StackConfig::vip() }.validate().is_err());
+        assert!(StackConfig { depth: 0, ..StackConfig::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn wrapped_stack_unwinds() {
+        use super::{ StackConfig, StackOverflow };
+
+        let rom = vec![
+            0x6D, 0x0D, // 0x200: VD = 13
+            0x22, 0x06, // 0x202: call 0x206
+            0x12, 0x04, // 0x204: done
+            0x7D, 0xFF, // 0x206: VD -= 1
+            0x3D, 0x00, // 0x208: skip if VD == 0
+            0x22, 0x06, // 0x20A: call 0x206, 13 deep in all
+            0x7E, 0x01, // 0x20C: VE += 1, counting the returns
+            0x00, 0xEE, // 0x20E: return
+        ];
+        let run = |stack: StackConfig| {
+            let mut vm = super::VM::new();
+            let mut interfaces = crate::system::Interfaces::new();
+            vm.set_stack_config(stack);
+            vm.load_rom(rom.clone());
+            for _ in 0..200 {
+                vm.tick(&mut interfaces).unwrap();
+            }
+            vm
+        };
+
+        // deep enough, every return goes back where it came from
+        let vm = run(StackConfig { depth: 13, ..StackConfig::vip() });
+        assert_eq!((vm.pc, vm.registers[0xE]), (0x204, 13));
+
+        // a level short, the ring goes round and the way out of the first call is lost, but every
+        // return still finds an address rather than underflowing
+        let vm = run(StackConfig { overflow: StackOverflow::Wrap, ..StackConfig::vip() });
+        assert!(vm.registers[0xE] > 13);
+        assert_ne!(vm.pc, 0x204);
+    }
+
+    #[test]
+    fn memory_map() {
+        use super::VmError;
+        use crate::memory::{ InterpreterWrites, MemoryFill, MemoryMap, Region };
+
+        let mut vm = super::VM::new();
+        let mut interfaces = crate::system::Interfaces::new();
+        vm.set_memory_map(MemoryMap { font_address: 0x50, interpreter_writes: InterpreterWrites::Protect, fill: MemoryFill::Byte(0xAA), ..MemoryMap::default() }).unwrap();
+        vm.load_rom(vec![
+            0x61, 0x01, // 0x200: V1 = 1
+            0xF1, 0x29, // 0x202: I = font character
+            0xF0, 0x55, // 0x204: store V0 over it
+        ]);
+        assert_eq!(vm.memory[0x00], 0xAA);
+        assert_eq!(vm.memory[0x55..0x5A], super::FONT[1]);
+        assert_eq!(vm.memory_map().region(0x205), Region::Program);
+        assert_eq!(vm.memory_map().region(0x206), Region::Free);
+
+        vm.tick(&mut interfaces).unwrap();
+        vm.tick(&mut interfaces).unwrap();
+        assert_eq!(vm.index, 0x55);
+        assert_eq!(vm.tick(&mut interfaces), Err(VmError::ProtectedWrite { address: 0x204, target: 0x55 }));
+        assert_eq!(vm.memory[0x55], 0x20);
+    }
+
+    #[test]
+    fn rejects_bad_memory_maps() {
+        use crate::memory::MemoryMap;
+
+        let mut vm = super::VM::new();
+        // past the end of memory, and where a rom would overwrite it
+        assert!(vm.set_memory_map(MemoryMap { font_address: 0xFC0, ..MemoryMap::default() }).is_err());
+        assert!(vm.set_memory_map(MemoryMap { font_address: 0x1B1, ..MemoryMap::default() }).is_err());
+        // leaving memory and the map as they were
+        assert_eq!(vm.memory_map().font_address, 0);
+        assert_eq!(vm.memory[0..5], super::FONT[0]);
+    }
 }
\ No newline at end of file